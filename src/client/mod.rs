@@ -1,27 +1,148 @@
 use crate::{
     config,
-    tunnel::{action, create_tun},
+    tunnel::{
+        action::{self, Message},
+        add_route6,
+        codec::{MessageCodec, TunnelStream},
+        create_tun,
+        keepalive::Keepalive,
+        tun_name, tun_stream, TunStream,
+    },
     AsyncReturn,
 };
 use futures::{future::FutureExt, pin_mut, select, SinkExt, StreamExt};
 use log::*;
+use openssl::rand::rand_bytes;
 use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
-use std::{pin::Pin, process::Command};
+use std::{fmt, pin::Pin, process::Command, time::Duration};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
     net::TcpStream,
+    signal,
+    sync::watch,
+    time::{self, Instant},
 };
 use tokio_openssl::SslStream;
-use tun::{AsyncDevice, TunPacket};
+use tokio_util::codec::Framed;
 
-async fn client_config(param: serde_json::Value) -> AsyncReturn<AsyncDevice> {
-    let ip = param.get("ip").unwrap().as_str().unwrap();
-    let routes = param.get("routes").unwrap().as_array().unwrap();
+// the process exit code when the server rejects us with an ERROR
+pub const EXIT_SERVER_ERROR: i32 = 2;
 
-    let tun = create_tun(ip)?;
+#[derive(Debug)]
+pub struct ServerError {
+    pub code: u16,
+    pub message: String,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Server error {}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+// the server closed the tunnel on purpose
+#[derive(Debug)]
+pub struct Disconnected {
+    pub reason: u16,
+}
+
+impl Disconnected {
+    // kicked or replaced clients are not wanted back
+    pub fn should_reconnect(&self) -> bool {
+        !matches!(
+            self.reason,
+            action::DISCONNECT_KICKED | action::DISCONNECT_REPLACED
+        )
+    }
+}
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Disconnected by server: {}",
+            action::disconnect_reason(self.reason)
+        )
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+// the tun went away under the client, a new connection has nowhere to go
+#[derive(Debug)]
+pub struct TunClosed;
+
+impl fmt::Display for TunClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Tun closed")
+    }
+}
+
+impl std::error::Error for TunClosed {}
+
+// the tun device and the routes through it,
+// kept across reconnects so applications only see a short stall
+struct Tunnel {
+    ip: String,
+    ip6: Option<String>,
+    routes: Vec<String>,
+    // presented on reconnect to keep the ip
+    token: String,
+    dev: TunStream,
+}
+
+async fn client_config(param: serde_json::Value, tunnel: &mut Option<Tunnel>) -> AsyncReturn<()> {
+    let ip = param
+        .get("ip")
+        .and_then(|ip| ip.as_str())
+        .ok_or("No ip in config")?;
+    // only when the server runs dual stack
+    let ip6 = param.get("ip6").and_then(|ip6| ip6.as_str());
+    let routes = param
+        .get("routes")
+        .and_then(|routes| routes.as_array())
+        .ok_or("No routes in config")?;
+
+    match tunnel {
+        Some(t) if t.ip == ip && t.ip6.as_deref() == ip6 => info!("Reuse tun {}", ip),
+        _ => {
+            if let Some(t) = tunnel.take() {
+                warn!(
+                    "Tunnel ip changes from {}/{:?} to {}/{:?}",
+                    t.ip, t.ip6, ip, ip6
+                );
+            }
+            tunnel.replace(Tunnel {
+                ip: ip.to_string(),
+                ip6: ip6.map(|ip6| ip6.to_string()),
+                routes: vec![],
+                token: String::new(),
+                dev: tun_stream(create_tun(ip, ip6)?),
+            });
+        }
+    }
+    let tunnel = tunnel.as_mut().unwrap();
+    tunnel.token = param
+        .get("token")
+        .and_then(|token| token.as_str())
+        .unwrap_or_default()
+        .to_string();
 
     for route in routes {
-        let route = route.as_str().unwrap();
+        let route = route.as_str().ok_or("Route is not a string")?;
+        if tunnel.routes.iter().any(|r| r == route) {
+            continue;
+        }
+        if route.contains(':') {
+            if tunnel.ip6.is_none() {
+                warn!("Skip ipv6 route {} without an ipv6 address", route);
+                continue;
+            }
+            add_route6(&tun_name(tunnel.dev.get_ref()), route).await?;
+            tunnel.routes.push(route.to_string());
+            continue;
+        }
         info!("route add {} gw {}", route, ip);
         let _ = Command::new("route")
             .arg("add")
@@ -29,102 +150,300 @@ async fn client_config(param: serde_json::Value) -> AsyncReturn<AsyncDevice> {
             .arg(route)
             .arg("gw")
             .arg(ip)
-            .output()
-            .expect("failed to add routes");
+            .output()?;
+        tunnel.routes.push(route.to_string());
     }
 
-    Ok(tun)
+    Ok(())
 }
 
-async fn start_connect(s: &mut BufReader<SslStream<TcpStream>>) -> AsyncReturn<AsyncDevice> {
-    let _ = s.write(&action::CONFIG_BUF).await;
-    let mut tun = None;
+async fn start_connect(s: &mut TunnelStream, tunnel: &mut Option<Tunnel>) -> AsyncReturn<u32> {
+    let token = tunnel.as_ref().map(|t| t.token.clone()).unwrap_or_default();
+    s.send(Message::ConfigRequest {
+        version: action::PROTOCOL_VERSION,
+        capabilities: action::CAPABILITIES,
+        token,
+    })
+    .await?;
+    let mut configured = false;
+    let mut negotiated = 0;
+    let mut assembler = None;
     loop {
-        let action = s.read_u8().await?;
-        match action {
-            action::CONFIG => {
-                let len = s.read_u16().await?;
-                let mut json_data: Vec<u8> = Vec::with_capacity(len as usize);
-
-                for _ in 0..len {
-                    json_data.push(s.read_u8().await?);
+        let msg = match s.next().await {
+            Some(msg) => msg?,
+            None => return Err("Connection closed during handshake".into()),
+        };
+        match msg {
+            Message::ConfigResponse {
+                version,
+                capabilities,
+                length,
+                digest,
+            } => {
+                action::check_negotiated(version, capabilities)?;
+                info!(
+                    "Protocol version {}, capabilities {:#x}",
+                    version, capabilities
+                );
+                negotiated = capabilities;
+                debug!("Config of {} bytes follows", length);
+                assembler.replace(action::ConfigAssembler::new(length, digest)?);
+            }
+            Message::ConfigData(chunk) => {
+                let json_str = match assembler.as_mut() {
+                    Some(assembler) => assembler.push(&chunk)?,
+                    None => return Err("Config data before config response".into()),
+                };
+                if let Some(json_str) = json_str {
+                    assembler = None;
+                    debug!("Get Config {}", json_str);
+                    client_config(serde_json::from_str(&json_str)?, tunnel).await?;
+                    configured = true;
+                    // the subnets behind us, routed to us by the server
+                    let subnets = config::get_local_subnets();
+                    if negotiated & action::CAP_ANNOUNCE != 0 && !subnets.is_empty() {
+                        info!("Announce {:?}", subnets);
+                        s.send(Message::Announce(subnets)).await?;
+                    }
+                    s.send(Message::Connect).await?;
                 }
-
-                let json_str = String::from_utf8(json_data).unwrap();
-                debug!("Get Config {}", json_str);
-                tun.replace(client_config(serde_json::from_str(&json_str).unwrap()).await?);
-                s.write(&action::CONNECT_BUF).await?;
             }
-            action::CONNECT => {
-                let _len = s.read_u16().await?;
-                let resp_magic = s.read_u32().await?;
-                assert!(resp_magic == action::CONNECT_MAGIC);
+            Message::Connect => {
                 break;
             }
-            _ => unimplemented!(),
+            Message::Error { code, message } => {
+                return Err(ServerError { code, message }.into());
+            }
+            msg => {
+                error!("Unexpected message {:?} during handshake", msg);
+                return Err("Unexpected message during handshake".into());
+            }
         }
     }
-    Ok(tun.unwrap())
+    if configured {
+        Ok(negotiated)
+    } else {
+        Err("Connected without config".into())
+    }
 }
 
-async fn client_loop(tun: AsyncDevice, ssl: BufReader<SslStream<TcpStream>>) -> AsyncReturn<()> {
-    let mut tun = tun.into_framed();
-    let mut ssl_buf = [0u8; 1600];
-
-    let (mut ssl_reader, mut ssl_writer) = tokio::io::split(ssl);
+async fn client_loop(
+    tun: &mut TunStream,
+    ssl: TunnelStream,
+    capabilities: u32,
+    mut shutdown: watch::Receiver<bool>,
+) -> AsyncReturn<()> {
+    let (mut ssl_writer, mut ssl_reader) = ssl.split();
+    let mut keepalive = Keepalive::new(capabilities);
+    let mut ka_ticker = keepalive.ticker();
+    let can_disconnect = capabilities & action::CAP_DISCONNECT != 0;
 
     loop {
         let tun_active = tun.next().fuse();
-        let ssl_active = ssl_reader.read(&mut ssl_buf).fuse();
+        let ssl_active = ssl_reader.next().fuse();
+        let ka_tick = ka_ticker.tick().fuse();
+        let shutdown_active = shutdown.changed().fuse();
 
-        pin_mut!(tun_active, ssl_active);
+        pin_mut!(tun_active, ssl_active, ka_tick, shutdown_active);
         select! {
             res  = tun_active => {
-                if let Ok(packet) = res.unwrap() {
-                    debug!("Write {:#04x?}", packet.get_bytes().len());
-                    ssl_writer.write_all(packet.get_bytes()).await.unwrap();
+                match res {
+                    Some(Ok(packet)) => {
+                        debug!("Write {:#04x?}", packet.len());
+                        ssl_writer.send(Message::Data(packet)).await?;
+                    }
+                    Some(Err(e)) => warn!("Read tun failed: {}", e),
+                    None => {
+                        if can_disconnect {
+                            let _ = ssl_writer
+                                .send(Message::Disconnect(action::DISCONNECT_SHUTDOWN))
+                                .await;
+                        }
+                        return Err(TunClosed.into());
+                    }
                 }
             },
             res  = ssl_active => {
-                let n = res.unwrap();
-                if 0 != n {
-                    debug!("Recv {:#04x?}", &ssl_buf.len());
-                    tun.send(TunPacket::new(ssl_buf[..n].to_vec())).await.unwrap();
-                } else {
-                    return Ok(());
+                match res {
+                    Some(Ok(Message::Data(pkt))) => {
+                        debug!("Recv {:#04x?}", pkt.len());
+                        tun.send(pkt).await?;
+                    }
+                    Some(Ok(Message::Ping(seq))) => {
+                        ssl_writer.send(Message::Pong(seq)).await?;
+                    }
+                    Some(Ok(Message::Pong(seq))) => keepalive.pong(seq),
+                    Some(Ok(Message::Error { code, message })) => {
+                        return Err(ServerError { code, message }.into());
+                    }
+                    Some(Ok(Message::Disconnect(reason))) => {
+                        info!("Server disconnected: {}", action::disconnect_reason(reason));
+                        return Err(Disconnected { reason }.into());
+                    }
+                    Some(Ok(msg)) => {
+                        warn!("Unexpected message {:?} from server", msg);
+                    }
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Err("Connection closed by server".into()),
+                }
+            },
+            _ = ka_tick => {
+                match keepalive.ping() {
+                    Ok(Some(ping)) => ssl_writer.send(ping).await?,
+                    Ok(None) => {}
+                    Err(e) => {
+                        if can_disconnect {
+                            let _ = ssl_writer
+                                .send(Message::Disconnect(action::DISCONNECT_IDLE_TIMEOUT))
+                                .await;
+                        }
+                        return Err(e);
+                    }
                 }
             },
+            _ = shutdown_active => {
+                if can_disconnect {
+                    let _ = ssl_writer
+                        .send(Message::Disconnect(action::DISCONNECT_SHUTDOWN))
+                        .await;
+                }
+                return Ok(());
+            },
         }
     }
 }
 
-pub async fn start() -> AsyncReturn<()> {
+async fn connect() -> AsyncReturn<TunnelStream> {
     let server_addr = config::get_server_ip();
     let connection = TcpStream::connect(&server_addr).await?;
     let ssl = {
-        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
-        connector
-            .set_private_key_file(config::get_key_file(), SslFiletype::PEM)
-            .unwrap();
-        connector
-            .set_certificate_file(config::get_cert_file(), SslFiletype::PEM)
-            .unwrap();
-        connector.set_ca_file(config::get_ca_file()).unwrap();
+        let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+        connector.set_private_key_file(config::get_key_file(), SslFiletype::PEM)?;
+        connector.set_certificate_file(config::get_cert_file(), SslFiletype::PEM)?;
+        connector.set_ca_file(config::get_ca_file())?;
         connector.set_verify(SslVerifyMode::PEER);
 
         connector
             .build()
-            .configure()
-            .unwrap()
+            .configure()?
             .verify_hostname(false)
-            .into_ssl(&server_addr)
-            .unwrap()
+            .into_ssl(&server_addr)?
     };
-    let mut connection = SslStream::new(ssl, connection).unwrap();
-    Pin::new(&mut connection).connect().await.unwrap();
-    info!("Client started");
+    let mut connection = SslStream::new(ssl, connection)?;
+    Pin::new(&mut connection).connect().await?;
+    info!("Client connected to {}", server_addr);
 
-    let mut stream = BufReader::new(connection);
-    let tun = start_connect(&mut stream).await?;
-    client_loop(tun, stream).await
+    Ok(Framed::new(connection, MessageCodec::new()))
+}
+
+// exponential backoff with jitter between reconnects
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        let initial = Duration::from_secs(config::get_reconnect_delay() as u64);
+        Backoff {
+            initial,
+            max: Duration::from_secs(config::get_reconnect_max_delay() as u64),
+            current: initial,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    // somewhere between half and all of the current delay
+    fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+
+        let mut rand = [0u8; 4];
+        let jitter = match rand_bytes(&mut rand) {
+            Ok(()) => u32::from_be_bytes(rand) as f64 / u32::MAX as f64,
+            Err(_) => 1.0,
+        };
+        delay / 2 + delay.mul_f64(jitter / 2.0)
+    }
+}
+
+// errors the server meant, reconnecting won't help
+fn is_fatal(e: &(dyn std::error::Error + 'static)) -> bool {
+    if e.is::<ServerError>() || e.is::<TunClosed>() {
+        return true;
+    }
+    match e.downcast_ref::<Disconnected>() {
+        Some(d) => !d.should_reconnect(),
+        None => false,
+    }
+}
+
+pub async fn start() -> AsyncReturn<()> {
+    let (shutdown_tx, mut shutdown) = watch::channel(false);
+    tokio::spawn(async move {
+        let _ = signal::ctrl_c().await;
+        info!("Client shutting down");
+        let _ = shutdown_tx.send(true);
+    });
+
+    let mut tunnel = None;
+    let mut backoff = Backoff::new();
+    loop {
+        // a shutdown cuts the handshake short
+        let handshake = {
+            let handshake = async {
+                let mut stream = connect().await?;
+                let capabilities = start_connect(&mut stream, &mut tunnel).await?;
+                Ok::<_, Box<dyn std::error::Error>>(Some((stream, capabilities)))
+            }
+            .fuse();
+            let mut watcher = shutdown.clone();
+            let interrupted = async move {
+                while !*watcher.borrow() {
+                    if watcher.changed().await.is_err() {
+                        break;
+                    }
+                }
+            }
+            .fuse();
+            pin_mut!(handshake, interrupted);
+            select! {
+                res = handshake => res,
+                _ = interrupted => Ok(None),
+            }
+        };
+
+        // once it's up, the session handles the shutdown itself
+        // and says DISCONNECT before it leaves
+        let res = match handshake {
+            Ok(Some((stream, capabilities))) => {
+                info!("Client started");
+                backoff.reset();
+                let tun = &mut tunnel.as_mut().unwrap().dev;
+                client_loop(tun, stream, capabilities, shutdown.clone()).await
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        match res {
+            Ok(()) => return Ok(()),
+            Err(e) if is_fatal(e.as_ref()) => return Err(e),
+            Err(e) => warn!("Connection lost: {}", e),
+        }
+
+        let delay = backoff.next_delay();
+        info!("Reconnect in {:?}", delay);
+        let wait = time::sleep_until(Instant::now() + delay).fuse();
+        let interrupted = shutdown.changed().fuse();
+        pin_mut!(wait, interrupted);
+        select! {
+            _ = wait => {},
+            _ = interrupted => return Ok(()),
+        }
+    }
 }
@@ -1,8 +1,12 @@
 use config::{Config, ConfigError, File, Value};
 
-use crate::tunnel::ippool::IpPool;
+use crate::tunnel::{ippool::IpPool, prefix::Prefix};
 use paste::paste;
-use std::{collections::HashMap, net::SocketAddr, path::Path};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
 
 struct PrivConfig {
     conf: Config,
@@ -46,11 +50,116 @@ fn config_check() {
 
         let _test_client_ip = IpPool::test_new("test", &get_client_ip_panic()).unwrap();
 
-        for test_route in get_client_routes_panic() {
-            let _test_pool = IpPool::test_new("test", &test_route).unwrap();
+        let mut names = vec![];
+        for pool in get_pools() {
+            if names.contains(&pool.name) {
+                panic!("Duplicate pool {}", pool.name);
+            }
+            if pool.name != DEFAULT_POOL
+                && pool.ou.is_none()
+                && pool.o.is_none()
+                && pool.san.is_none()
+            {
+                panic!(
+                    "Pool {} needs one of ou, o or san to select clients",
+                    pool.name
+                );
+            }
+
+            let test_client_ip = IpPool::test_new("test", &pool.client_ip).unwrap();
+            assert!(!test_client_ip.is_v6() && !test_client_ip.is_host_mode());
+
+            if !pool.client_ip6.is_empty() {
+                let test_client_ip6 = IpPool::test_new("test", &pool.client_ip6).unwrap();
+                assert!(test_client_ip6.is_v6() && !test_client_ip6.is_host_mode());
+            }
+
+            for test_route in &pool.client_routes {
+                let _test_pool = IpPool::test_new("test", test_route).unwrap();
+            }
+
+            for test_route in pool.allowed_routes.iter().flatten() {
+                let _test_prefix = Prefix::parse(test_route).unwrap();
+            }
+            names.push(pool.name);
+        }
+
+        let server_ip6 = get_server_ip6();
+        if !server_ip6.is_empty() && server_ip6.parse::<Ipv6Addr>().is_err() {
+            panic!("Invalid server_ip6 {}", server_ip6);
+        }
+
+        for (name, routes) in get_client_policies() {
+            for route in routes {
+                if Prefix::parse(&route).is_err() {
+                    panic!("Invalid route {} in the policy of {}", route, name);
+                }
+            }
+        }
+
+        for (name, subnets) in get_delegated_subnets() {
+            for subnet in subnets {
+                if Prefix::parse(&subnet).is_err() {
+                    panic!("Invalid subnet {} delegated to {}", subnet, name);
+                }
+            }
+        }
+
+        for (name, ip) in get_static_ips() {
+            if ip.parse::<IpAddr>().is_err() {
+                panic!("Invalid static ip {} for {}", ip, name);
+            }
+        }
+
+        if get_resume_grace() < 0 {
+            panic!("resume_grace cannot be negative");
+        }
+
+        if get_ip_quarantine() < 0 {
+            panic!("ip_quarantine cannot be negative");
+        }
+
+        if get_lease_time() <= 0 {
+            panic!("lease_time must be positive");
+        }
+
+        if get_icmp_rate() < 0 {
+            panic!("icmp_rate cannot be negative");
+        }
+
+        if get_session_queue() <= 0 {
+            panic!("session_queue must be positive");
+        }
+        if get_tun_queues() <= 0 {
+            panic!("tun_queues must be positive");
+        }
+
+        let queue_drop = get_queue_drop();
+        if queue_drop != "tail" && queue_drop != "oldest" {
+            panic!("queue_drop must be tail or oldest, not {}", queue_drop);
         }
     } else {
         let _test_server_ip = get_server_ip_panic().parse::<SocketAddr>().unwrap();
+
+        if get_reconnect_delay() <= 0 {
+            panic!("reconnect_delay must be positive");
+        }
+
+        for subnet in get_local_subnets() {
+            if Prefix::parse(&subnet).is_err() {
+                panic!("Invalid local subnet {}", subnet);
+            }
+        }
+        if get_reconnect_max_delay() < get_reconnect_delay() {
+            panic!("reconnect_max_delay cannot be less than reconnect_delay");
+        }
+    }
+
+    if get_keepalive_interval() <= 0 {
+        panic!("keepalive_interval must be positive");
+    }
+    if get_keepalive_retries() <= 0 {
+        panic!("keepalive_retries must be positive");
     }
 
     let ca_file_path = get_ca_file_panic();
@@ -90,6 +199,94 @@ pub fn init_from_file(filename: &str) -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+// the pool of clients no other pool selects, from the top level keys
+pub const DEFAULT_POOL: &str = "default";
+
+// a group of clients with its own addresses and routes
+#[derive(Clone)]
+pub struct PoolConfig {
+    pub name: String,
+    pub client_ip: String,
+    pub client_ip6: String,
+    pub client_routes: Vec<String>,
+    // the destinations the clients may reach, the pushed routes if not set
+    pub allowed_routes: Option<Vec<String>>,
+    // the clients may reach other clients, if `client_to_client` is on
+    pub client_to_client: bool,
+    // certificate attributes selecting the clients, any of them will do
+    pub ou: Option<String>,
+    pub o: Option<String>,
+    // a pattern with `*` wildcards matched against the subject alternative names
+    pub san: Option<String>,
+}
+
+fn str_array(array: Value) -> Vec<String> {
+    array
+        .into_array()
+        .unwrap()
+        .into_iter()
+        .map(|i| i.into_str().unwrap())
+        .collect()
+}
+
+fn pool_config(pool: Value) -> PoolConfig {
+    let mut pool = pool.into_table().unwrap();
+    let mut take_str = |key: &str| pool.remove(key).map(|v| v.into_str().unwrap());
+    let name = take_str("name").unwrap_or_else(|| panic!("Pool without a name"));
+    let client_ip =
+        take_str("client_ip").unwrap_or_else(|| panic!("Pool {} without client_ip", name));
+    let client_ip6 = take_str("client_ip6").unwrap_or_default();
+    let ou = take_str("ou");
+    let o = take_str("o");
+    let san = take_str("san");
+    let client_routes = pool
+        .remove("client_routes")
+        .map(str_array)
+        .unwrap_or_default();
+    let allowed_routes = pool.remove("allowed_routes").map(str_array);
+    let client_to_client = pool
+        .remove("client_to_client")
+        .map(|v| v.into_bool().unwrap())
+        .unwrap_or(true);
+    if name == DEFAULT_POOL {
+        panic!("Pool name {} is taken by the top level keys", DEFAULT_POOL);
+    }
+    PoolConfig {
+        name,
+        client_ip,
+        client_ip6,
+        client_routes,
+        allowed_routes,
+        client_to_client,
+        ou,
+        o,
+        san,
+    }
+}
+
+// the pools in the order they are tried, the default one last
+pub fn get_pools() -> Vec<PoolConfig> {
+    let mut pools: Vec<PoolConfig> = unsafe { CONFIG.unwrap().get_array("pools") }
+        .unwrap_or_default()
+        .into_iter()
+        .map(pool_config)
+        .collect();
+    pools.push(PoolConfig {
+        name: DEFAULT_POOL.to_string(),
+        client_ip: get_client_ip(),
+        client_ip6: get_client_ip6(),
+        client_routes: get_client_routes(),
+        allowed_routes: unsafe { CONFIG.unwrap().get_array("allowed_routes") }
+            .ok()
+            .map(|routes| routes.into_iter().map(|i| i.into_str().unwrap()).collect()),
+        client_to_client: true,
+        ou: None,
+        o: None,
+        san: None,
+    });
+    pools
+}
+
 pub fn is_server() -> bool {
     unsafe { CONFIG.unwrap().get_bool("server").unwrap_or(false) }
 }
@@ -111,6 +308,38 @@ macro_rules! impl_getter {
         }
     };
 
+    (_ i64, $field:ident) => {
+        unsafe { CONFIG.unwrap().get_int(stringify!($field)).unwrap() }
+    };
+
+    (_ bool, $field:ident) => {
+        unsafe { CONFIG.unwrap().get_bool(stringify!($field)).unwrap() }
+    };
+
+    (_ HashMap<String, String>, $field:ident) => {
+        unsafe {
+            CONFIG
+                .unwrap()
+                .get_table(stringify!($field))
+                .unwrap()
+                .into_iter()
+                .map(|(k, v)| (k, v.into_str().unwrap()))
+                .collect()
+        }
+    };
+
+    (_ HashMap<String, Vec<String>>, $field:ident) => {
+        unsafe {
+            CONFIG
+                .unwrap()
+                .get_table(stringify!($field))
+                .unwrap()
+                .into_iter()
+                .map(|(k, v)| (k, str_array(v)))
+                .collect()
+        }
+    };
+
     (_ String, $field:ident, $default: expr) => {
         unsafe {
             CONFIG
@@ -132,12 +361,55 @@ macro_rules! impl_getter {
         }
     };
 
+    (_ HashMap<String, String>, $field:ident, $default: expr) => {
+        unsafe {
+            CONFIG
+                .unwrap()
+                .get_table(stringify!($field))
+                .map(|t| {
+                    t.into_iter()
+                        .map(|(k, v)| (k, v.into_str().unwrap()))
+                        .collect()
+                })
+                .unwrap_or($default)
+        }
+    };
+
+    (_ HashMap<String, Vec<String>>, $field:ident, $default: expr) => {
+        unsafe {
+            CONFIG
+                .unwrap()
+                .get_table(stringify!($field))
+                .map(|t| t.into_iter().map(|(k, v)| (k, str_array(v))).collect())
+                .unwrap_or($default)
+        }
+    };
+
+    (_ bool, $field:ident, $default: expr) => {
+        unsafe {
+            CONFIG
+                .unwrap()
+                .get_bool(stringify!($field))
+                .unwrap_or($default)
+        }
+    };
+
+    (_ i64, $field:ident, $default: expr) => {
+        unsafe {
+            CONFIG
+                .unwrap()
+                .get_int(stringify!($field))
+                .unwrap_or($default)
+        }
+    };
+
     ($ret:ty, $field:ident, $default: expr) => {
         paste! {
             pub fn [<get_ $field>]() -> $ret {
                 impl_getter!(_ $ret, $field, $default)
             }
 
+            #[allow(dead_code)]
             fn [<get_ $field _panic>]() -> $ret {
                 impl_getter!(_ $ret, $field)
             }
@@ -148,7 +420,26 @@ macro_rules! impl_getter {
 impl_getter!(String, listen_ip, "0.0.0.0:443".to_string());
 impl_getter!(String, server_ip, "173.75.2.1".to_string());
 impl_getter!(String, client_ip, "173.75.1.0/24".to_string());
+impl_getter!(String, server_ip6, "".to_string());
+impl_getter!(String, client_ip6, "".to_string());
 impl_getter!(Vec<String>, client_routes, vec![]);
+impl_getter!(HashMap<String, String>, static_ips, HashMap::new());
+impl_getter!(HashMap<String, Vec<String>>, client_policies, HashMap::new());
+impl_getter!(HashMap<String, Vec<String>>, delegated_subnets, HashMap::new());
+impl_getter!(bool, client_to_client, false);
+impl_getter!(i64, icmp_rate, 10);
+impl_getter!(i64, session_queue, 100);
+impl_getter!(String, queue_drop, "tail".to_string());
+impl_getter!(i64, tun_queues, 1);
 impl_getter!(String, ca_file, "ca.cer".to_string());
 impl_getter!(String, key_file, "key.pem".to_string());
 impl_getter!(String, cert_file, "cert.pem".to_string());
+impl_getter!(i64, keepalive_interval, 10);
+impl_getter!(i64, keepalive_retries, 3);
+impl_getter!(i64, resume_grace, 60);
+impl_getter!(i64, ip_quarantine, 0);
+impl_getter!(String, lease_file, "".to_string());
+impl_getter!(i64, lease_time, 86400);
+impl_getter!(i64, reconnect_delay, 1);
+impl_getter!(i64, reconnect_max_delay, 60);
+impl_getter!(Vec<String>, local_subnets, vec![]);
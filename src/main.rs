@@ -1,5 +1,6 @@
 use clap::clap_app;
 use env_logger::Env;
+use log::*;
 
 mod client;
 mod config;
@@ -63,8 +64,12 @@ async fn main() -> AsyncReturn<()> {
     parse_args()?;
     if config::is_server() {
         server::start().await?;
-    } else {
-        client::start().await?;
+    } else if let Err(e) = client::start().await {
+        if let Some(e) = e.downcast_ref::<client::ServerError>() {
+            error!("{}", e);
+            std::process::exit(client::EXIT_SERVER_ERROR);
+        }
+        return Err(e);
     }
     Ok(())
 }
@@ -1,21 +1,134 @@
+mod policy;
+mod queue;
+mod resume;
 mod route;
 mod session;
+mod site;
+mod spoof;
 
-use crate::tunnel::{create_tun, ippool};
+use crate::tunnel::{action, add_route6, codec::MessageCodec, create_tun_queues, ippool, tun_name};
 use crate::AsyncReturn;
-use crate::{config, server::session::SessionBuilder};
+use crate::{
+    config::{self, PoolConfig},
+    server::session::SessionBuilder,
+};
+use futures::{future::FutureExt, pin_mut, select};
 use log::*;
 use openssl::nid::Nid;
 use openssl::ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
-use route::Router;
+use openssl::x509::X509Ref;
+use resume::ResumeTable;
+use route::{Router, RouterHandle};
+use site::SiteTable;
 use std::pin::Pin;
-use tokio::io::BufReader;
-use tokio::net::TcpListener;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::{signal, time};
 use tokio_openssl::SslStream;
+use tokio_util::codec::Framed;
+
+// what the client certificate says about its owner
+struct Identity {
+    name: String,
+    ou: Vec<String>,
+    o: Vec<String>,
+    san: Vec<String>,
+}
+
+impl Identity {
+    fn from_cert(cert: &X509Ref) -> Option<Identity> {
+        let entries = |nid| -> Vec<String> {
+            cert.subject_name()
+                .entries_by_nid(nid)
+                .filter_map(|entry| entry.data().as_utf8().ok())
+                .map(|data| data.to_string())
+                .collect()
+        };
+        let name = entries(Nid::COMMONNAME).into_iter().next()?;
+        let san = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        name.dnsname()
+                            .or_else(|| name.email())
+                            .or_else(|| name.uri())
+                    })
+                    .map(|name| name.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(Identity {
+            name,
+            ou: entries(Nid::ORGANIZATIONALUNITNAME),
+            o: entries(Nid::ORGANIZATIONNAME),
+            san,
+        })
+    }
+}
+
+// `*` in `pattern` stands for any run of characters
+fn wildcard_match(pattern: &str, s: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if parts.len() == 1 {
+        return s == pattern;
+    }
+    if s.len() < first.len() + last.len() || !s.starts_with(first) || !s.ends_with(last) {
+        return false;
+    }
+    let mut rest = &s[first.len()..s.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+// the first pool selecting the client, the default one takes the rest
+fn select_pool(pools: Vec<PoolConfig>, id: &Identity) -> PoolConfig {
+    let selects = |pool: &PoolConfig| {
+        if pool.name == config::DEFAULT_POOL {
+            return true;
+        }
+        matches!(&pool.ou, Some(ou) if id.ou.contains(ou))
+            || matches!(&pool.o, Some(o) if id.o.contains(o))
+            || matches!(&pool.san, Some(san) if id.san.iter().any(|name| wildcard_match(san, name)))
+    };
+    pools
+        .into_iter()
+        .find(selects)
+        .unwrap_or_else(|| panic!("No default pool"))
+}
 
 pub async fn start() -> AsyncReturn<()> {
-    let _ = ippool::init("client IP pool", &config::get_client_ip()).unwrap();
-    let router = Router::new(create_tun(&config::get_server_ip()).unwrap());
+    let server_ip6 = config::get_server_ip6();
+    let (tun, queues) = create_tun_queues(
+        &config::get_server_ip(),
+        Some(server_ip6.as_str()).filter(|ip| !ip.is_empty()),
+        config::get_tun_queues() as usize,
+    )
+    .unwrap();
+    let tun_name = tun_name(&tun);
+    for pool in config::get_pools() {
+        ippool::init(&pool.name, &pool.client_ip)?;
+        if !pool.client_ip6.is_empty() {
+            ippool::init6(&pool.name, &pool.client_ip6)?;
+            // the whole prefix goes through the tun, the router picks the session
+            add_route6(&tun_name, &pool.client_ip6).await?;
+        }
+        info!("Pool {}: {} {}", pool.name, pool.client_ip, pool.client_ip6);
+    }
+    for (name, ip) in config::get_static_ips() {
+        ippool::reserve_client_ip(&ip)?;
+        info!("Reserve {} for {}", ip, name);
+    }
+    tokio::spawn(ippool::flush_leases());
+    let router = Router::new(tun, queues);
 
     let listen_addr = config::get_listen_ip();
     let listener = TcpListener::bind(&listen_addr).await?;
@@ -39,36 +152,121 @@ pub async fn start() -> AsyncReturn<()> {
     // Start tun loop
     let router = router.start().await?;
 
+    let resume = ResumeTable::new(Duration::from_secs(config::get_resume_grace() as u64));
+    let sites = SiteTable::new();
+
+    // Sessions leave with the reason sent here
+    let (shutdown, _) = broadcast::channel(1);
+    let ctrl_c = signal::ctrl_c().fuse();
+    pin_mut!(ctrl_c);
+
     // Start server loop
     loop {
-        let (socket, client) = listener.accept().await?;
-        let tls_acceptor = tls_acceptor.clone();
-        let router = router.clone();
-        info!("Accept client {}", client);
-
-        tokio::spawn(async move {
-            // ssl accept
-            let ssl = Ssl::new(tls_acceptor.context()).unwrap();
-            let mut tls_stream = SslStream::new(ssl, socket).unwrap();
-            Pin::new(&mut tls_stream).accept().await.unwrap();
-
-            // retrieve the common name
-            let client_cert = tls_stream.ssl().peer_certificate().unwrap();
-            let x509_name = client_cert.subject_name();
-            let name = x509_name
-                .entries_by_nid(Nid::COMMONNAME)
-                .next()
-                .unwrap_or_else(|| panic!("No common name found"))
-                .data();
-
-            // session build
-            let client = SessionBuilder::new()
-                .name(&name.as_utf8().unwrap().to_string())
-                .server_ip(&config::get_server_ip())
-                .stream(BufReader::new(tls_stream))
-                .router(router)
-                .build();
-            let _ = client.start().await;
-        });
+        let accept = listener.accept().fuse();
+        pin_mut!(accept);
+        select! {
+            res = accept => {
+                let (socket, client) = res?;
+                let tls_acceptor = tls_acceptor.clone();
+                let router = router.clone();
+                let resume = resume.clone();
+                let sites = sites.clone();
+                let tun_name = tun_name.clone();
+                let shutdown = shutdown.subscribe();
+                info!("Accept client {}", client);
+
+                tokio::spawn(async move {
+                    if let Err(e) = accept_client(tls_acceptor, socket, router, resume, sites, &tun_name, shutdown).await {
+                        error!("Client {}: {}", client, e);
+                    }
+                });
+            },
+            _ = ctrl_c => break,
+        }
+    }
+
+    info!("Server shutting down");
+    let _ = shutdown.send(action::DISCONNECT_SHUTDOWN);
+    // give the sessions a moment to say goodbye
+    for _ in 0..30 {
+        if shutdown.receiver_count() == 0 {
+            break;
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    ippool::save_leases();
+    Ok(())
+}
+
+async fn accept_client(
+    tls_acceptor: SslAcceptor,
+    socket: TcpStream,
+    router: RouterHandle,
+    resume: ResumeTable,
+    sites: SiteTable,
+    tun_name: &str,
+    shutdown: broadcast::Receiver<u16>,
+) -> AsyncReturn<()> {
+    // ssl accept
+    let ssl = Ssl::new(tls_acceptor.context())?;
+    let mut tls_stream = SslStream::new(ssl, socket)?;
+    Pin::new(&mut tls_stream).accept().await?;
+
+    // retrieve the common name and what selects the pool
+    let id = tls_stream
+        .ssl()
+        .peer_certificate()
+        .and_then(|client_cert| Identity::from_cert(&client_cert));
+    let mut stream = Framed::new(tls_stream, MessageCodec::new());
+    let id = match id {
+        Some(id) => id,
+        None => {
+            session::send_error(&mut stream, action::ERR_NO_IDENTITY, "No common name found").await;
+            return Err("No common name found".into());
+        }
+    };
+    let pool = select_pool(config::get_pools(), &id);
+
+    // session build
+    let client = SessionBuilder::new()
+        .name(&id.name)
+        .server_ip(&config::get_server_ip())
+        .tun_name(tun_name)
+        .pool(pool)
+        .stream(stream)
+        .router(router)
+        .resume(resume)
+        .sites(sites)
+        .shutdown(shutdown)
+        .build()
+        .await?;
+    client.start().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard() {
+        assert!(wildcard_match("vpn.example.com", "vpn.example.com"));
+        assert!(!wildcard_match("vpn.example.com", "vpn.example.org"));
+        assert!(wildcard_match("*.example.com", "a.example.com"));
+        assert!(wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(wildcard_match("*@example.com", "ops@example.com"));
+        assert!(wildcard_match("a*b*c", "abc"));
+        assert!(wildcard_match("a*b*c", "axxbyyc"));
+        assert!(!wildcard_match("a*b*c", "axxcyyb"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("**", "anything"));
+    }
+
+    #[test]
+    fn wildcard_overlapping_ends() {
+        // the first and last part cannot share characters
+        assert!(!wildcard_match("ab*ba", "aba"));
+        assert!(wildcard_match("ab*ba", "abba"));
+        assert!(!wildcard_match("a*b*b", "ab"));
     }
 }
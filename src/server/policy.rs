@@ -0,0 +1,94 @@
+use log::*;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    config::{self, PoolConfig},
+    tunnel::prefix::Prefix,
+};
+
+// where the destinations of a client come from
+#[derive(Clone, Copy, PartialEq)]
+pub enum Source {
+    // its own entry in `client_policies`
+    Client,
+    // the `allowed_routes` of its pool
+    Pool,
+    // the `routes` pushed to it
+    Routes,
+}
+
+// the destinations a client may reach through the gateway,
+// enforced by the router on every packet of the session
+pub struct Policy {
+    name: String,
+    allowed: Vec<Prefix>,
+    source: Source,
+    // `client_to_client` is on for the client
+    peers: bool,
+    // packets to other destinations, dropped
+    dropped: AtomicU64,
+}
+
+impl Policy {
+    // the client's own entry in `client_policies` if any,
+    // otherwise the `allowed_routes` of its pool, otherwise the `routes` pushed to it
+    pub fn new(name: &str, pool: &PoolConfig, routes: &[String]) -> Policy {
+        let (source, routes) = match config::get_client_policies().remove(name) {
+            Some(routes) => (Source::Client, routes),
+            None => match &pool.allowed_routes {
+                Some(routes) => (Source::Pool, routes.clone()),
+                None => (Source::Routes, routes.to_vec()),
+            },
+        };
+        let peers = config::get_client_to_client() && pool.client_to_client;
+        Policy::with(name, &routes, source, peers)
+    }
+
+    pub fn with(name: &str, routes: &[String], source: Source, peers: bool) -> Policy {
+        let allowed = routes
+            .iter()
+            .filter_map(|route| match Prefix::parse(route) {
+                Ok(prefix) => Some(prefix),
+                Err(e) => {
+                    error!("Policy of {}: skip {}: {}", name, route, e);
+                    None
+                }
+            })
+            .collect();
+        Policy {
+            name: name.to_string(),
+            allowed,
+            source,
+            peers,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn allows(&self, dst: &IpAddr) -> bool {
+        self.allowed.iter().any(|prefix| prefix.contains(dst))
+    }
+
+    // another client at `dst`, the client's own entry wins over the switch,
+    // the `allowed_routes` of its pool narrow it
+    pub fn allows_peer(&self, dst: &IpAddr) -> bool {
+        match self.source {
+            Source::Client => self.allows(dst),
+            Source::Pool => self.peers && self.allows(dst),
+            Source::Routes => self.peers,
+        }
+    }
+
+    // counts a dropped packet, returns the count so far
+    pub fn drop_packet(&self) -> u64 {
+        self.dropped.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,202 @@
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::Notify;
+
+// what goes when a full queue gets one more packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DropPolicy {
+    // the new packet
+    Tail,
+    // the packet waiting the longest
+    Oldest,
+}
+
+impl DropPolicy {
+    pub fn parse(policy: &str) -> Result<DropPolicy, String> {
+        match policy {
+            "tail" => Ok(DropPolicy::Tail),
+            "oldest" => Ok(DropPolicy::Oldest),
+            _ => Err(format!("Unknown drop policy {}", policy)),
+        }
+    }
+}
+
+// result of `PacketQueue::push`
+#[derive(PartialEq)]
+pub enum Pushed {
+    Queued,
+    // queued, or not, at the cost of a packet
+    Overflow,
+    // the session is gone
+    Closed,
+}
+
+struct PacketQueueInner {
+    packets: Mutex<VecDeque<Bytes>>,
+    capacity: usize,
+    policy: DropPolicy,
+    notify: Notify,
+    closed: AtomicBool,
+    // packets dropped because the session is behind
+    overflow: AtomicU64,
+}
+
+// the packets from the router to one session, the router never waits on it
+pub struct PacketQueue(Arc<PacketQueueInner>);
+
+impl PacketQueue {
+    pub fn new(capacity: usize, policy: DropPolicy) -> PacketQueue {
+        PacketQueue(Arc::new(PacketQueueInner {
+            packets: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            policy,
+            notify: Notify::new(),
+            closed: AtomicBool::new(false),
+            overflow: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn push(&self, pkt: Bytes) -> Pushed {
+        if self.0.closed.load(Ordering::Relaxed) {
+            return Pushed::Closed;
+        }
+        let pushed = {
+            let mut packets = self.0.packets.lock().unwrap();
+            let full = packets.len() >= self.0.capacity;
+            if full {
+                self.0.overflow.fetch_add(1, Ordering::Relaxed);
+            }
+            if !full || self.0.policy == DropPolicy::Oldest {
+                if full {
+                    packets.pop_front();
+                }
+                packets.push_back(pkt);
+            }
+            match full {
+                true => Pushed::Overflow,
+                false => Pushed::Queued,
+            }
+        };
+        self.0.notify.notify_one();
+        pushed
+    }
+
+    // the next packet, None once the queue is closed and drained
+    pub async fn pop(&self) -> Option<Bytes> {
+        loop {
+            {
+                let mut packets = self.0.packets.lock().unwrap();
+                if let Some(pkt) = packets.pop_front() {
+                    return Some(pkt);
+                }
+            }
+            if self.0.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            self.0.notify.notified().await;
+        }
+    }
+
+    // the session ends, the router drops what comes next
+    pub fn close(&self) {
+        self.0.closed.store(true, Ordering::Relaxed);
+        self.0.packets.lock().unwrap().clear();
+        self.0.notify.notify_one();
+    }
+
+    pub fn overflow(&self) -> u64 {
+        self.0.overflow.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for PacketQueue {
+    fn clone(&self) -> Self {
+        PacketQueue(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkt(n: u8) -> Bytes {
+        Bytes::from(vec![n])
+    }
+
+    #[tokio::test]
+    async fn in_order() {
+        let queue = PacketQueue::new(4, DropPolicy::Tail);
+        for n in 0..3 {
+            assert!(queue.push(pkt(n)) == Pushed::Queued);
+        }
+        assert_eq!(queue.pop().await, Some(pkt(0)));
+        assert_eq!(queue.pop().await, Some(pkt(1)));
+        assert_eq!(queue.pop().await, Some(pkt(2)));
+        assert_eq!(queue.overflow(), 0);
+    }
+
+    #[tokio::test]
+    async fn tail_drop() {
+        let queue = PacketQueue::new(2, DropPolicy::Tail);
+        for n in 0..4 {
+            queue.push(pkt(n));
+        }
+        assert_eq!(queue.overflow(), 2);
+        assert_eq!(queue.pop().await, Some(pkt(0)));
+        assert_eq!(queue.pop().await, Some(pkt(1)));
+    }
+
+    #[tokio::test]
+    async fn oldest_drop() {
+        let queue = PacketQueue::new(2, DropPolicy::Oldest);
+        assert!(queue.push(pkt(0)) == Pushed::Queued);
+        assert!(queue.push(pkt(1)) == Pushed::Queued);
+        assert!(queue.push(pkt(2)) == Pushed::Overflow);
+        assert!(queue.push(pkt(3)) == Pushed::Overflow);
+        assert_eq!(queue.overflow(), 2);
+        assert_eq!(queue.pop().await, Some(pkt(2)));
+        assert_eq!(queue.pop().await, Some(pkt(3)));
+    }
+
+    #[tokio::test]
+    async fn closed() {
+        let queue = PacketQueue::new(4, DropPolicy::Tail);
+        queue.push(pkt(0));
+        // what waits is dropped with the session
+        queue.close();
+        assert_eq!(queue.pop().await, None);
+        assert!(queue.push(pkt(1)) == Pushed::Closed);
+        assert_eq!(queue.overflow(), 0);
+    }
+
+    #[tokio::test]
+    async fn pop_waits_for_a_packet() {
+        let queue = PacketQueue::new(4, DropPolicy::Tail);
+        let pusher = queue.clone();
+        let popped = tokio::spawn(async move { queue.pop().await });
+        tokio::task::yield_now().await;
+        pusher.push(pkt(7));
+        assert_eq!(popped.await.unwrap(), Some(pkt(7)));
+    }
+
+    #[tokio::test]
+    async fn close_wakes_pop() {
+        let queue = PacketQueue::new(4, DropPolicy::Tail);
+        let closer = queue.clone();
+        let popped = tokio::spawn(async move { queue.pop().await });
+        tokio::task::yield_now().await;
+        closer.close();
+        assert_eq!(popped.await.unwrap(), None);
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!(DropPolicy::parse("tail"), Ok(DropPolicy::Tail));
+        assert_eq!(DropPolicy::parse("oldest"), Ok(DropPolicy::Oldest));
+        assert!(DropPolicy::parse("random").is_err());
+    }
+}
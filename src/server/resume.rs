@@ -0,0 +1,327 @@
+use log::*;
+use openssl::rand::rand_bytes;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::mpsc, time};
+
+use crate::{tunnel::action, AsyncReturn};
+
+use super::ippool;
+
+static SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+enum State {
+    // owned by a running session, which leaves on a DISCONNECT reason sent here
+    Live(u64, mpsc::Sender<u16>),
+    // the session ended, waiting for the client to come back
+    Parked(u64),
+}
+
+struct Entry {
+    name: String,
+    ip: String,
+    ip6: Option<String>,
+    state: State,
+}
+
+struct ResumeTableInner {
+    grace: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+// what happens to the session's ip when it ends
+pub enum Closed {
+    // kept for the client to resume within the grace window
+    Parked,
+    // back to the pool
+    Released,
+    // a newer session of the same client owns it now
+    Replaced,
+}
+
+// what a session gets from the table
+pub struct Registration {
+    pub ip: String,
+    pub ip6: Option<String>,
+    pub token: String,
+    pub id: u64,
+    // the addresses of a replaced session the new one does not keep,
+    // for the caller to unroute and release
+    pub orphans: Vec<String>,
+}
+
+fn release(ips: &[String]) {
+    for ip in ips {
+        if let Err(e) = ippool::release_client_ip(ip) {
+            error!("Release {} failed: {}", ip, e);
+        }
+    }
+}
+
+// resumption tokens, so a reconnecting client keeps its tunnel ip
+pub struct ResumeTable(Arc<ResumeTableInner>);
+
+fn new_token() -> AsyncReturn<String> {
+    let mut buf = [0u8; 16];
+    rand_bytes(&mut buf)?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+impl ResumeTable {
+    pub fn new(grace: Duration) -> ResumeTable {
+        ResumeTable(Arc::new(ResumeTableInner {
+            grace,
+            entries: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.0.grace.is_zero()
+    }
+
+    // registers a session on its newly assigned addresses.
+    // A static ip given to the same client again replaces the old session,
+    // the ipv6 address of which is left to the caller
+    pub fn open(
+        &self,
+        name: &str,
+        ip: &str,
+        ip6: Option<String>,
+        kick: mpsc::Sender<u16>,
+    ) -> AsyncReturn<Registration> {
+        let token = new_token()?;
+        let id = SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        let mut orphans = vec![];
+        let mut entries = self.0.entries.lock().unwrap();
+        entries.retain(|_, entry| {
+            if entry.ip != ip {
+                return true;
+            }
+            if let State::Live(old, addr) = &entry.state {
+                info!("Session {} of {} is replaced", old, name);
+                let _ = addr.try_send(action::DISCONNECT_REPLACED);
+            }
+            orphans.extend(entry.ip6.take());
+            false
+        });
+        entries.insert(
+            token.clone(),
+            Entry {
+                name: name.to_string(),
+                ip: ip.to_string(),
+                ip6: ip6.clone(),
+                state: State::Live(id, kick),
+            },
+        );
+        Ok(Registration {
+            ip: ip.to_string(),
+            ip6,
+            token,
+            id,
+            orphans,
+        })
+    }
+
+    // hands the addresses behind `token` to a new session of the same client
+    // with a new token
+    pub fn resume(
+        &self,
+        token: &str,
+        name: &str,
+        kick: mpsc::Sender<u16>,
+    ) -> AsyncReturn<Option<Registration>> {
+        let mut entries = self.0.entries.lock().unwrap();
+        match entries.get(token) {
+            Some(entry) if entry.name == name => {}
+            Some(_) => {
+                warn!("Client {} presents a token of another client", name);
+                return Ok(None);
+            }
+            None => {
+                debug!("Client {} presents an unknown or expired token", name);
+                return Ok(None);
+            }
+        }
+
+        let mut entry = entries.remove(token).unwrap();
+        if let State::Live(id, old) = &entry.state {
+            info!("Session {} of {} is replaced", id, name);
+            let _ = old.try_send(action::DISCONNECT_REPLACED);
+        }
+        let new_token = new_token()?;
+        let id = SESSION_ID.fetch_add(1, Ordering::Relaxed);
+        entry.state = State::Live(id, kick);
+        let registration = Registration {
+            ip: entry.ip.clone(),
+            ip6: entry.ip6.clone(),
+            token: new_token.clone(),
+            id,
+            orphans: vec![],
+        };
+        entries.insert(new_token, entry);
+        Ok(Some(registration))
+    }
+
+    // called when session `id` ends, parks its ip if it may be resumed
+    pub fn close(&self, token: &str, id: u64, resumable: bool) -> Closed {
+        let mut entries = self.0.entries.lock().unwrap();
+        let entry = match entries.get_mut(token) {
+            Some(entry) if matches!(entry.state, State::Live(owner, _) if owner == id) => entry,
+            _ => return Closed::Replaced,
+        };
+
+        if !resumable || !self.enabled() {
+            entries.remove(token);
+            return Closed::Released;
+        }
+
+        entry.state = State::Parked(id);
+        let table = self.clone();
+        let token = token.to_string();
+        tokio::spawn(async move {
+            time::sleep(table.0.grace).await;
+            table.expire(&token, id);
+        });
+        Closed::Parked
+    }
+
+    fn expire(&self, token: &str, id: u64) {
+        let entry = {
+            let mut entries = self.0.entries.lock().unwrap();
+            match entries.get(token) {
+                Some(Entry {
+                    state: State::Parked(owner),
+                    ..
+                }) if *owner == id => entries.remove(token).unwrap(),
+                _ => return,
+            }
+        };
+        info!("Resumption of {}({}) expires", entry.name, entry.ip);
+        let mut ips = vec![entry.ip];
+        ips.extend(entry.ip6);
+        release(&ips);
+    }
+}
+
+impl Clone for ResumeTable {
+    fn clone(&self) -> ResumeTable {
+        ResumeTable(Arc::clone(&self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kick() -> (mpsc::Sender<u16>, mpsc::Receiver<u16>) {
+        mpsc::channel(1)
+    }
+
+    fn open(table: &ResumeTable, ip: &str, ip6: &str) -> (Registration, mpsc::Receiver<u16>) {
+        let (addr, kicked) = kick();
+        let registration = table.open("a", ip, Some(ip6.to_string()), addr).unwrap();
+        (registration, kicked)
+    }
+
+    #[tokio::test]
+    async fn park_and_resume() {
+        let table = ResumeTable::new(Duration::from_secs(60));
+        let (first, _kicked) = open(&table, "10.0.0.2", "fd00::2");
+        assert!(matches!(
+            table.close(&first.token, first.id, true),
+            Closed::Parked
+        ));
+        // only its own client may come back
+        assert!(table.resume(&first.token, "b", kick().0).unwrap().is_none());
+        let second = table.resume(&first.token, "a", kick().0).unwrap().unwrap();
+        assert_eq!(second.ip, "10.0.0.2");
+        assert_eq!(second.ip6.as_deref(), Some("fd00::2"));
+        assert_ne!(second.token, first.token);
+        assert_ne!(second.id, first.id);
+        // a token is good once
+        assert!(table.resume(&first.token, "a", kick().0).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn not_resumable() {
+        let table = ResumeTable::new(Duration::from_secs(60));
+        let (first, _kicked) = open(&table, "10.0.0.2", "fd00::2");
+        assert!(matches!(
+            table.close(&first.token, first.id, false),
+            Closed::Released
+        ));
+        assert!(table.resume(&first.token, "a", kick().0).unwrap().is_none());
+
+        // without a grace window nothing is parked
+        let table = ResumeTable::new(Duration::ZERO);
+        assert!(!table.enabled());
+        let (first, _kicked) = open(&table, "10.0.0.2", "fd00::2");
+        assert!(matches!(
+            table.close(&first.token, first.id, true),
+            Closed::Released
+        ));
+    }
+
+    #[tokio::test]
+    async fn resume_replaces_a_live_session() {
+        let table = ResumeTable::new(Duration::from_secs(60));
+        let (first, mut kicked) = open(&table, "10.0.0.2", "fd00::2");
+        let second = table.resume(&first.token, "a", kick().0).unwrap().unwrap();
+        assert_eq!(kicked.recv().await, Some(action::DISCONNECT_REPLACED));
+        assert!(second.orphans.is_empty());
+        // the old session leaves the addresses alone
+        assert!(matches!(
+            table.close(&first.token, first.id, true),
+            Closed::Replaced
+        ));
+    }
+
+    #[tokio::test]
+    async fn static_ip_replaces_a_live_session() {
+        let table = ResumeTable::new(Duration::from_secs(60));
+        let (first, mut kicked) = open(&table, "10.0.0.2", "fd00::2");
+        let (second, _kicked) = open(&table, "10.0.0.2", "fd00::3");
+        assert_eq!(kicked.recv().await, Some(action::DISCONNECT_REPLACED));
+        assert_eq!(second.orphans, vec!["fd00::2".to_string()]);
+        assert!(matches!(
+            table.close(&first.token, first.id, true),
+            Closed::Replaced
+        ));
+        assert!(matches!(
+            table.close(&second.token, second.id, true),
+            Closed::Parked
+        ));
+    }
+
+    #[tokio::test]
+    async fn parked_session_expires() {
+        let table = ResumeTable::new(Duration::from_millis(20));
+        let (first, _kicked) = open(&table, "10.0.0.2", "fd00::2");
+        assert!(matches!(
+            table.close(&first.token, first.id, true),
+            Closed::Parked
+        ));
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(table.resume(&first.token, "a", kick().0).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn resumed_session_outlives_the_grace() {
+        let table = ResumeTable::new(Duration::from_millis(20));
+        let (first, _kicked) = open(&table, "10.0.0.2", "fd00::2");
+        table.close(&first.token, first.id, true);
+        let second = table.resume(&first.token, "a", kick().0).unwrap().unwrap();
+        // the timer of the first park finds a live session
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(matches!(
+            table.close(&second.token, second.id, false),
+            Closed::Released
+        ));
+    }
+}
@@ -1,130 +1,513 @@
-use etherparse::{Ipv4HeaderSlice, Ipv6HeaderSlice};
+use bytes::Bytes;
+use futures::StreamExt;
 use futures::{future::FutureExt, pin_mut, select};
-use futures::{SinkExt, StreamExt};
 use log::*;
 use std::net::IpAddr;
-use std::{collections::HashMap, sync::RwLock};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, RwLock,
+};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::mpsc;
-use tun::{AsyncDevice, TunPacket};
+use tun::AsyncDevice;
 
-use crate::AsyncReturn;
+use crate::{
+    config,
+    tunnel::{
+        packet,
+        prefix::{Prefix, PrefixMap},
+        tun_stream, TunQueue, TunStream,
+    },
+    AsyncReturn,
+};
+
+use super::{
+    policy::Policy,
+    queue::{PacketQueue, Pushed},
+};
 
 pub enum RouteMsg {
-    AddRoute(String, mpsc::Sender<TunPacket>),
+    // an address of the session
+    AddRoute(String, PacketQueue),
+    // a subnet behind the session
+    AddSubnet(String, PacketQueue),
     DelRoute(String),
-    Forwarding(TunPacket),
 }
 
-struct RouterInner {
-    e: Option<AsyncDevice>,
-    t: RwLock<HashMap<IpAddr, mpsc::Sender<TunPacket>>>,
+// a packet from a client, let through if its policy allows the destination,
+// to another client without a round trip through the kernel
+pub type Forward = (Bytes, Arc<Policy>);
+
+// what the sessions talk to, the control task for their routes
+// and the worker their packets go to the tun through
+pub struct RouterHandle {
+    pub control: mpsc::Sender<RouteMsg>,
+    workers: Arc<Vec<mpsc::Sender<Forward>>>,
 }
 
-impl RouterInner {
-    pub fn new(e: AsyncDevice) -> RouterInner {
-        RouterInner {
-            e: Some(e),
-            t: RwLock::new(HashMap::new()),
+impl RouterHandle {
+    // the worker of session `id`, the packets of a session keep their order on it
+    pub fn worker(&self, id: u64) -> mpsc::Sender<Forward> {
+        self.workers[(id % self.workers.len() as u64) as usize].clone()
+    }
+}
+
+impl Clone for RouterHandle {
+    fn clone(&self) -> Self {
+        RouterHandle {
+            control: self.control.clone(),
+            workers: self.workers.clone(),
         }
     }
+}
 
-    pub fn add(&self, ip: &str, session_addr: mpsc::Sender<TunPacket>) {
-        let mut t = self.t.write().unwrap();
-        let ip = ip.parse::<IpAddr>().unwrap();
+// packets the router dropped, by reason, logged on SIGUSR1
+#[derive(Default)]
+struct DropStats {
+    // too short for its ip header
+    malformed: AtomicU64,
+    // neither ipv4 nor ipv6
+    unknown_version: AtomicU64,
+    // to an address without a session
+    no_route: AtomicU64,
+    // the session is behind, its own count is in the queue
+    queue_full: AtomicU64,
+    // from a client to a destination its policy forbids
+    forbidden: AtomicU64,
+}
+
+impl DropStats {
+    fn count(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn report(&self) {
+        info!(
+            "Router drops: malformed {}, unknown version {}, no route {}, queue full {}, forbidden {}",
+            self.malformed.load(Ordering::Relaxed),
+            self.unknown_version.load(Ordering::Relaxed),
+            self.no_route.load(Ordering::Relaxed),
+            self.queue_full.load(Ordering::Relaxed),
+            self.forbidden.load(Ordering::Relaxed),
+        );
+    }
+}
+
+// where the router sends the packets to a prefix
+#[derive(Clone)]
+struct Route {
+    queue: PacketQueue,
+    // a subnet announced by the client, reached under the policy
+    // like any other destination, not as a peer
+    subnet: bool,
+}
+
+// ICMP errors sent in the current second, at most `rate` of them
+struct IcmpLimit {
+    rate: u32,
+    second: Instant,
+    sent: u32,
+}
+
+impl IcmpLimit {
+    fn new(rate: u32) -> IcmpLimit {
+        IcmpLimit {
+            rate,
+            second: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    fn allow(&mut self) -> bool {
+        if self.second.elapsed() >= Duration::from_secs(1) {
+            self.second = Instant::now();
+            self.sent = 0;
+        }
+        if self.sent >= self.rate {
+            return false;
+        }
+        self.sent += 1;
+        true
+    }
+}
+
+// what the workers share, the routing table first of all
+struct RouterState {
+    // the session of every host address or prefix
+    t: RwLock<PrefixMap<Route>>,
+    stats: DropStats,
+    // packets from a client straight to another one
+    peer: AtomicU64,
+    // the sources of the ICMP errors, the server tun addresses
+    icmp_from: (Option<IpAddr>, Option<IpAddr>),
+    icmp_limit: Mutex<IcmpLimit>,
+}
+
+// the address part of `server_ip` or `server_ip6`
+fn host(ip: &str) -> Option<IpAddr> {
+    ip.split('/').next()?.parse().ok()
+}
+
+impl RouterState {
+    pub fn new() -> RouterState {
+        RouterState::with(
+            (
+                host(&config::get_server_ip()),
+                host(&config::get_server_ip6()),
+            ),
+            config::get_icmp_rate() as u32,
+        )
+    }
 
-        t.insert(ip, session_addr);
+    fn with(icmp_from: (Option<IpAddr>, Option<IpAddr>), icmp_rate: u32) -> RouterState {
+        RouterState {
+            t: RwLock::new(PrefixMap::new()),
+            stats: DropStats::default(),
+            peer: AtomicU64::new(0),
+            icmp_from,
+            icmp_limit: Mutex::new(IcmpLimit::new(icmp_rate)),
+        }
+    }
+
+    pub fn add(&self, ip: &str, queue: PacketQueue, subnet: bool) {
+        let ip = match Prefix::parse(ip) {
+            Ok(ip) => ip,
+            Err(e) => {
+                error!("Cannot route {}: {}", ip, e);
+                return;
+            }
+        };
+        let mut t = self.t.write().unwrap();
+        t.insert(ip, Route { queue, subnet });
     }
 
     pub fn del(&self, ip: &str) {
-        let ip = ip.parse::<IpAddr>().unwrap();
+        let ip = match Prefix::parse(ip) {
+            Ok(ip) => ip,
+            Err(e) => {
+                error!("Cannot unroute {}: {}", ip, e);
+                return;
+            }
+        };
         let mut t = self.t.write().unwrap();
         t.remove(&ip);
     }
 
-    pub async fn routing(&self, pkt: TunPacket) {
-        let version = pkt.get_bytes()[0] >> 4;
+    fn route_of(&self, dst: &IpAddr) -> Option<Route> {
+        let route_table = self.t.read().unwrap();
+        route_table.lookup(dst).cloned()
+    }
 
-        if let Some(ip) = match version {
-            4 => {
-                let ip4h = Ipv4HeaderSlice::from_slice(pkt.get_bytes()).unwrap();
-                Some(ip4h.destination_addr().into())
+    // hands `pkt` to a session without waiting for it
+    fn deliver(&self, session_addr: &PacketQueue, dst: &IpAddr, pkt: Bytes) {
+        match session_addr.push(pkt) {
+            Pushed::Queued => {}
+            Pushed::Overflow => {
+                DropStats::count(&self.stats.queue_full);
+                debug!("Session of {} is behind, drop packet", dst);
             }
-            6 => {
-                let ip6h = Ipv6HeaderSlice::from_slice(pkt.get_bytes()).unwrap();
-                Some(ip6h.destination_addr().into())
+            // the session is gone, its DelRoute is on the way
+            Pushed::Closed => {
+                DropStats::count(&self.stats.no_route);
+                debug!("Session of {} is closed, drop packet", dst);
             }
-            x => {
+        }
+    }
+
+    // tells the sender of `pkt` nobody is there, rate limited
+    fn unreachable(&self, pkt: &[u8]) -> Option<Bytes> {
+        let from = match pkt[0] >> 4 {
+            4 => self.icmp_from.0?,
+            _ => self.icmp_from.1?,
+        };
+        // before the reply is built, a flood costs no allocations
+        if !self.icmp_limit.lock().unwrap().allow() {
+            return None;
+        }
+        packet::unreachable(pkt, from).map(Bytes::from)
+    }
+
+    // a packet read from the tun, to the session of its destination,
+    // returns the ICMP error to write back if there is none
+    pub fn routing(&self, pkt: Bytes) -> Option<Bytes> {
+        let dst = match pkt.first().map(|b| b >> 4) {
+            Some(4) | Some(6) => packet::destination(&pkt),
+            Some(x) => {
+                DropStats::count(&self.stats.unknown_version);
                 debug!("Unimplement packet version {}", x);
+                return None;
+            }
+            None => None,
+        };
+        let dst = match dst {
+            Some(dst) => dst,
+            None => {
+                DropStats::count(&self.stats.malformed);
+                debug!("Drop malformed packet of {} bytes", pkt.len());
+                return None;
+            }
+        };
+
+        match self.route_of(&dst) {
+            Some(route) => {
+                self.deliver(&route.queue, &dst, pkt);
                 None
             }
-        } {
-            let session_addr = {
-                let route_table = self.t.read().unwrap();
-                route_table.get(&ip).cloned()
-            };
-            if let Some(session_addr) = session_addr {
-                session_addr.send(pkt).await.unwrap();
-            } else {
-                warn!("No session for ip {:x?}", ip);
+            None => {
+                DropStats::count(&self.stats.no_route);
+                debug!("No session for ip {}", dst);
+                self.unreachable(&pkt)
+            }
+        }
+    }
+
+    // a packet from a client, checked against its policy,
+    // returned if it goes to the tun
+    fn forwarding(&self, pkt: Bytes, policy: &Policy) -> Option<Bytes> {
+        let dst = match packet::destination(&pkt) {
+            Some(dst) => dst,
+            None => {
+                DropStats::count(&self.stats.malformed);
+                debug!("Drop malformed packet of {}", policy.name());
+                return None;
+            }
+        };
+        // to another client, straight to its session
+        let route = self.route_of(&dst);
+        let allowed = match &route {
+            Some(route) if !route.subnet => policy.allows_peer(&dst),
+            _ => policy.allows(&dst),
+        };
+        if !allowed {
+            DropStats::count(&self.stats.forbidden);
+            let dropped = policy.drop_packet();
+            debug!(
+                "Drop packet of {} to {}, not allowed ({} so far)",
+                policy.name(),
+                dst,
+                dropped
+            );
+            return None;
+        }
+        match route {
+            Some(route) => {
+                self.peer.fetch_add(1, Ordering::Relaxed);
+                self.deliver(&route.queue, &dst, pkt);
+                None
             }
+            None => Some(pkt),
+        }
+    }
+}
+
+// reads and writes one queue of the tun, with the packets of the sessions
+// the control task hands to it
+async fn worker<T>(
+    state: Arc<RouterState>,
+    mut tun: TunStream<T>,
+    mut forward: mpsc::Receiver<Forward>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let tun_input = tun.next().fuse();
+        let forward = forward.recv().fuse();
+        pin_mut!(tun_input, forward);
+        select! {
+            res  = tun_input => {
+                match res {
+                    Some(Ok(packet)) => {
+                        // debug!("Read {:#04x?} from tun", packet.len());
+                        if let Some(reply) = state.routing(packet) {
+                            let _ = tun.send(reply).await;
+                        }
+                    }
+                    Some(Err(e)) => warn!("Read tun failed: {}", e),
+                    None => {
+                        error!("Tun closed, router stops");
+                        break;
+                    }
+                }
+            },
+
+            res = forward => {
+                match res {
+                    Some((pkt, policy)) => {
+                        if let Some(pkt) = state.forwarding(pkt, &policy) {
+                            debug!("Write {:#04x?} to tun", pkt.len());
+                            let _ = tun.send(pkt).await;
+                        }
+                    }
+                    None => break,
+                }
+            },
+        }
+    }
+}
+
+struct RouterInner {
+    e: Option<AsyncDevice>,
+    // the other queues of a multi-queue tun
+    queues: Vec<TunQueue>,
+    state: Arc<RouterState>,
+}
+
+impl RouterInner {
+    pub fn new(e: AsyncDevice, queues: Vec<TunQueue>) -> RouterInner {
+        RouterInner {
+            e: Some(e),
+            queues,
+            state: Arc::new(RouterState::new()),
         }
     }
 
-    pub async fn start(mut self) -> AsyncReturn<mpsc::Sender<RouteMsg>> {
+    // one worker per tun queue taking the packets of its sessions,
+    // routes are added and deleted by a single control task
+    // in the order the sessions send them
+    pub async fn start(mut self) -> AsyncReturn<RouterHandle> {
         if self.e.is_none() {
             panic!("No underlay device");
         }
         let (msg_addr, mut msg_rcv) = mpsc::channel::<RouteMsg>(100);
+        let mut usr1 = signal(SignalKind::user_defined1())?;
+
+        let mut workers = vec![];
+        let (forward, forward_rcv) = mpsc::channel(100);
+        let tun = tun_stream(self.e.take().unwrap());
+        tokio::spawn(worker(self.state.clone(), tun, forward_rcv));
+        workers.push(forward);
+        for queue in self.queues.drain(..) {
+            let (forward, forward_rcv) = mpsc::channel(100);
+            tokio::spawn(worker(self.state.clone(), queue.into_stream(), forward_rcv));
+            workers.push(forward);
+        }
+        info!("Router starts {} workers", workers.len());
 
-        let tun = self.e.take().unwrap();
+        let state = self.state;
         tokio::spawn(async move {
-            let mut tun = tun.into_framed();
             loop {
-                let tun_input = tun.next().fuse();
                 let route_msg = msg_rcv.recv().fuse();
-                pin_mut!(tun_input, route_msg);
+                let report = usr1.recv().fuse();
+                pin_mut!(route_msg, report);
                 select! {
-                    res  = tun_input => {
-                        if let Ok(packet) = res.unwrap() {
-                            // debug!("Read {:#04x?} from tun", packet.get_bytes().len());
-                            self.routing(packet).await;
-                        }
-                    },
-
                     res = route_msg => {
-                        if let Some(msg) = res {
-                            match msg {
-                                RouteMsg::Forwarding(pkt) => {
-                                    debug!("Write {:#04x?} to tun", pkt.get_bytes().len());
-                                    let _ = tun.send(pkt).await;
-                                }
-                                RouteMsg::AddRoute(ip, session_addr) => {
-                                    debug!("Add ip {} to routing", ip);
-                                    self.add(&ip, session_addr);
-                                }
-                                RouteMsg::DelRoute(ip) => {
-                                    debug!("Del ip {} from routing", ip);
-                                    self.del(&ip);
-                                }
+                        match res {
+                            Some(RouteMsg::AddRoute(ip, session_addr)) => {
+                                debug!("Add ip {} to routing", ip);
+                                state.add(&ip, session_addr, false);
+                            }
+                            Some(RouteMsg::AddSubnet(subnet, session_addr)) => {
+                                debug!("Add subnet {} to routing", subnet);
+                                state.add(&subnet, session_addr, true);
                             }
+                            Some(RouteMsg::DelRoute(ip)) => {
+                                debug!("Del ip {} from routing", ip);
+                                state.del(&ip);
+                            }
+                            None => break,
                         }
-                    }
+                    },
+
+                    _ = report => {
+                        state.stats.report();
+                        info!("Router client to client: {}", state.peer.load(Ordering::Relaxed));
+                    },
                 }
             }
         });
 
-        Ok(msg_addr)
+        Ok(RouterHandle {
+            control: msg_addr,
+            workers: Arc::new(workers),
+        })
     }
 }
 
 pub struct Router(RouterInner);
 
 impl Router {
-    pub fn new(endpoint: AsyncDevice) -> Router {
-        Router(RouterInner::new(endpoint))
+    pub fn new(endpoint: AsyncDevice, queues: Vec<TunQueue>) -> Router {
+        Router(RouterInner::new(endpoint, queues))
     }
 
-    pub async fn start(self) -> AsyncReturn<mpsc::Sender<RouteMsg>> {
+    pub async fn start(self) -> AsyncReturn<RouterHandle> {
         self.0.start().await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{policy::Source, queue::DropPolicy};
+
+    // an ipv4 header from the client to `dst`
+    fn packet(dst: [u8; 4]) -> Bytes {
+        let mut pkt = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 17, 0, 0, 10, 0, 0, 2];
+        pkt.extend_from_slice(&dst);
+        Bytes::from(pkt)
+    }
+
+    // a router with another client on 10.0.0.3 and a subnet behind it
+    fn router() -> RouterState {
+        let state = RouterState::with((None, None), 0);
+        let queue = PacketQueue::new(16, DropPolicy::Tail);
+        state.add("10.0.0.3", queue.clone(), false);
+        state.add("192.168.3.0/24", queue, true);
+        state
+    }
+
+    fn policy(routes: &[&str], source: Source, peers: bool) -> Policy {
+        let routes: Vec<String> = routes.iter().map(|route| route.to_string()).collect();
+        Policy::with("a", &routes, source, peers)
+    }
+
+    // where a packet to `dst` goes: the tun, a peer or nowhere
+    fn forward(policy: &Policy, dst: [u8; 4]) -> &'static str {
+        let state = router();
+        match state.forwarding(packet(dst), policy) {
+            Some(_) => "tun",
+            None if state.peer.load(Ordering::Relaxed) == 1 => "peer",
+            None => "dropped",
+        }
+    }
+
+    #[test]
+    fn peers_under_the_switch() {
+        let on = policy(&["172.16.0.0/16"], Source::Routes, true);
+        assert_eq!(forward(&on, [10, 0, 0, 3]), "peer");
+        assert_eq!(forward(&on, [172, 16, 0, 1]), "tun");
+        let off = policy(&["172.16.0.0/16", "10.0.0.0/24"], Source::Routes, false);
+        assert_eq!(forward(&off, [10, 0, 0, 3]), "dropped");
+    }
+
+    #[test]
+    fn client_policy_wins_over_the_switch() {
+        // held to one host, the switch opens no other client
+        let held = policy(&["172.16.0.1"], Source::Client, true);
+        assert_eq!(forward(&held, [10, 0, 0, 3]), "dropped");
+        assert_eq!(forward(&held, [172, 16, 0, 1]), "tun");
+        // a peer listed for the client is reached with the switch off
+        let listed = policy(&["10.0.0.3"], Source::Client, false);
+        assert_eq!(forward(&listed, [10, 0, 0, 3]), "peer");
+    }
+
+    #[test]
+    fn pool_routes_narrow_the_switch() {
+        let pool = policy(&["10.0.0.0/24"], Source::Pool, true);
+        assert_eq!(forward(&pool, [10, 0, 0, 3]), "peer");
+        let elsewhere = policy(&["172.16.0.0/16"], Source::Pool, true);
+        assert_eq!(forward(&elsewhere, [10, 0, 0, 3]), "dropped");
+        let off = policy(&["10.0.0.0/24"], Source::Pool, false);
+        assert_eq!(forward(&off, [10, 0, 0, 3]), "dropped");
+    }
+
+    #[test]
+    fn subnets_under_the_policy() {
+        let allowed = policy(&["192.168.3.0/24"], Source::Routes, false);
+        assert_eq!(forward(&allowed, [192, 168, 3, 1]), "peer");
+        let other = policy(&["172.16.0.0/16"], Source::Routes, true);
+        assert_eq!(forward(&other, [192, 168, 3, 1]), "dropped");
+    }
+}
@@ -1,45 +1,97 @@
-use futures::{executor, future::FutureExt, pin_mut, select};
+use futures::{executor, future::FutureExt, pin_mut, select, SinkExt, StreamExt};
 use log::*;
 use serde_json::json;
+use std::sync::Arc;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    net::TcpStream,
     process::Command,
-    sync::mpsc,
+    sync::{broadcast, mpsc},
 };
-use tokio_openssl::SslStream;
-use tun::TunPacket;
 
-use crate::{config, tunnel::action, AsyncReturn};
+use crate::{
+    config::{self, PoolConfig},
+    tunnel::{
+        action::{self, Message},
+        add_route6,
+        codec::TunnelStream,
+        del_route6, del_route6_blocking,
+        keepalive::Keepalive,
+        prefix::Prefix,
+    },
+    AsyncReturn,
+};
 
-use super::{ippool, route::RouteMsg};
+use super::{
+    ippool,
+    policy::Policy,
+    queue::{DropPolicy, PacketQueue},
+    resume::{Closed, Registration, ResumeTable},
+    route::{Forward, RouteMsg, RouterHandle},
+    site::SiteTable,
+    spoof::SpoofGuard,
+};
 
 // the main structure of the session
 #[allow(dead_code)]
 struct SessionInner {
+    id: u64,
     name: String,
     client_ip: String,
+    client_ip6: Option<String>,
     server_ip: String,
-    stream: BufReader<SslStream<TcpStream>>,
+    // routes pushed to the client, from its pool
+    routes: Vec<String>,
+    policy: Arc<Policy>,
+    // the packets the router has for the client
+    queue: PacketQueue,
+    // subnets behind the clients, by session
+    sites: SiteTable,
+    tun_name: String,
+    spoof: SpoofGuard,
+    version: u16,
+    capabilities: u32,
+    token: String,
+    // the reason the client left with, if it said DISCONNECT
+    disconnect: Option<u16>,
+    stream: TunnelStream,
     router: mpsc::Sender<RouteMsg>,
+    // the router worker the packets of the client go through
+    forward: mpsc::Sender<Forward>,
+    resume: ResumeTable,
+    shutdown: Option<broadcast::Receiver<u16>>,
+    kick: Option<mpsc::Receiver<u16>>,
 }
 
 impl SessionInner {
     pub async fn start(mut self) -> AsyncReturn<()> {
-        let (addr, tun) = mpsc::channel(100);
+        let addr = self.queue.clone();
 
-        let _ = self
-            .router
-            .send(RouteMsg::AddRoute(self.client_ip.clone(), addr))
-            .await;
+        for ip in self.addresses() {
+            let _ = self
+                .router
+                .send(RouteMsg::AddRoute(ip.clone(), addr.clone()))
+                .await;
+        }
 
-        self.handle_params().await?;
-        self.main_loop(tun).await
+        let shutdown = self
+            .shutdown
+            .take()
+            .unwrap_or_else(|| panic!("No shutdown"));
+        let kick = self.kick.take().unwrap_or_else(|| panic!("No kick"));
+        self.handle_params(&addr).await?;
+        self.main_loop(addr, shutdown, kick).await
     }
 }
 
 impl SessionInner {
-    async fn server_config(&self) -> AsyncReturn<Vec<u8>> {
+    // the ip and, on dual stack, the ipv6 address of the client
+    fn addresses(&self) -> Vec<String> {
+        std::iter::once(&self.client_ip)
+            .chain(&self.client_ip6)
+            .cloned()
+            .collect()
+    }
+
+    async fn server_config(&self) -> AsyncReturn<String> {
         info!("route add {} gw {}", self.client_ip, self.server_ip);
         let _ = Command::new("route")
             .arg("add")
@@ -48,90 +100,212 @@ impl SessionInner {
             .arg("gw")
             .arg(&self.server_ip)
             .output()
-            .await
-            .expect("failed to add routes");
+            .await?;
 
-        let ret = json!({
+        let mut ret = json!({
             "ip": &self.client_ip,
-            "routes": config::get_client_routes(),
-        })
-        .to_string();
+            "routes": &self.routes,
+        });
+        if let Some(ip6) = &self.client_ip6 {
+            ret["ip6"] = json!(ip6);
+        }
+        if self.capabilities & action::CAP_RESUME != 0 && self.resume.enabled() {
+            ret["token"] = json!(&self.token);
+        }
+        let ret = ret.to_string();
         debug!("Send {:?}", ret);
-        let mut ret_buf = vec![
-            action::CONFIG,
-            ((ret.len() & 0xff00) >> 16).try_into().unwrap(),
-            (ret.len() & 0xff).try_into().unwrap(),
-        ];
-        ret_buf.extend(ret.as_bytes());
-        Ok(ret_buf)
+        Ok(ret)
     }
 
-    async fn handle_params(&mut self) -> AsyncReturn<()> {
-        let client_param = self.server_config().await?;
-        loop {
-            let action = self.stream.read_u8().await.unwrap();
-            match action {
-                action::CONFIG => {
-                    info!("Connection start");
-                    let _len = self.stream.read_u16().await.unwrap();
-                    let config_magic = self.stream.read_u32().await.unwrap();
-                    if config_magic != action::CONFIG_MAGIC {
-                        error!(
-                            "Invalid config magic, expect {:x}, got {:x}",
-                            action::CONFIG_MAGIC,
-                            config_magic
-                        );
-                        return AsyncReturn::Err("Invalid config magic".into());
-                    }
+    // says goodbye if the client understands it
+    async fn disconnect(&mut self, reason: u16) {
+        info!(
+            "Session {}({}) disconnecting: {}",
+            self.name,
+            self.client_ip,
+            action::disconnect_reason(reason)
+        );
+        if self.capabilities & action::CAP_DISCONNECT != 0 {
+            let _ = self.stream.send(Message::Disconnect(reason)).await;
+        }
+    }
 
-                    let _ = self.stream.write(&client_param).await;
+    // tells the client why before closing
+    async fn reject(&mut self, code: u16, reason: String) -> AsyncReturn<()> {
+        error!("Session {}({}): {}", self.name, self.client_ip, reason);
+        send_error(&mut self.stream, code, &reason).await;
+        Err(reason.into())
+    }
+
+    // installs the subnets behind a site-to-site client,
+    // each of them within one delegated to it
+    async fn announce(&mut self, subnets: Vec<String>, addr: &PacketQueue) -> AsyncReturn<()> {
+        let allowed: Vec<Prefix> = config::get_delegated_subnets()
+            .remove(&self.name)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|subnet| Prefix::parse(subnet).ok())
+            .collect();
+        let mut prefixes = vec![];
+        for subnet in &subnets {
+            let prefix = match Prefix::parse(subnet).map_err(|e| e.to_string()) {
+                Ok(prefix) => prefix,
+                Err(e) => {
+                    let reason = format!("Invalid subnet {}: {}", subnet, e);
+                    return self.reject(action::ERR_PROTOCOL, reason).await;
                 }
-                action::CONNECT => {
-                    let _len = self.stream.read_u16().await.unwrap();
-                    let connect_magic = self.stream.read_u32().await.unwrap();
-                    if connect_magic != action::CONNECT_MAGIC {
-                        error!(
-                            "Invalid connect magic, expect {:x}, got {:x}",
-                            action::CONNECT_MAGIC,
-                            connect_magic
-                        );
-                        return AsyncReturn::Err("Invalid connect magic".into());
-                    }
-                    let _ = self.stream.write(&action::CONNECT_BUF).await;
+            };
+            if !allowed.iter().any(|allowed| allowed.covers(&prefix)) {
+                let reason = format!("Subnet {} is not delegated to {}", subnet, self.name);
+                return self.reject(action::ERR_FORBIDDEN, reason).await;
+            }
+            prefixes.push(prefix);
+        }
+        let stale = match self.sites.announce(self.id, &self.name, &prefixes) {
+            Ok(stale) => stale,
+            Err(e) => return self.reject(action::ERR_FORBIDDEN, e).await,
+        };
+        for prefix in &stale {
+            let _ = self
+                .router
+                .send(RouteMsg::DelRoute(prefix.to_string()))
+                .await;
+            if let Err(e) = kernel_route("del", prefix, &self.server_ip, &self.tun_name).await {
+                warn!("Session {}: {}", self.name, e);
+            }
+        }
+
+        for prefix in &prefixes {
+            let _ = self
+                .router
+                .send(RouteMsg::AddSubnet(prefix.to_string(), addr.clone()))
+                .await;
+            if let Err(e) = kernel_route("add", prefix, &self.server_ip, &self.tun_name).await {
+                warn!("Session {}: {}", self.name, e);
+            }
+        }
+        info!("Session {} announces {:?}", self.name, prefixes);
+        Ok(())
+    }
+
+    async fn handle_params(&mut self, addr: &PacketQueue) -> AsyncReturn<()> {
+        let client_param = match self.server_config().await.map_err(|e| e.to_string()) {
+            Ok(param) => param,
+            Err(e) => return self.reject(action::ERR_INTERNAL, e).await,
+        };
+        for msg in action::config_messages(self.version, self.capabilities, &client_param) {
+            self.stream.feed(msg).await?;
+        }
+        self.stream.flush().await?;
+
+        let mut announced = false;
+        loop {
+            let msg = match self.stream.next().await {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => return self.reject(action::ERR_PROTOCOL, e.to_string()).await,
+                None => return Err("Connection closed during handshake".into()),
+            };
+            match msg {
+                // once, a second one is unexpected
+                Message::Announce(subnets)
+                    if self.capabilities & action::CAP_ANNOUNCE != 0 && !announced =>
+                {
+                    self.announce(subnets, addr).await?;
+                    announced = true;
+                }
+                Message::Connect => {
+                    self.stream.send(Message::Connect).await?;
                     // Tunnel setup
-                    break;
+                    return Ok(());
+                }
+                msg => {
+                    let reason = format!("Unexpected message {:?} during handshake", msg);
+                    return self.reject(action::ERR_PROTOCOL, reason).await;
                 }
-                _ => unimplemented!(),
             }
         }
-        Ok(())
     }
 
-    async fn main_loop(&mut self, mut tun: mpsc::Receiver<TunPacket>) -> AsyncReturn<()> {
-        let mut ssl_buf = [0u8; 1600];
+    async fn main_loop(
+        &mut self,
+        tun: PacketQueue,
+        mut shutdown: broadcast::Receiver<u16>,
+        mut kick: mpsc::Receiver<u16>,
+    ) -> AsyncReturn<()> {
+        let mut keepalive = Keepalive::new(self.capabilities);
+        let mut ka_ticker = keepalive.ticker();
 
         loop {
-            let ssl_rx = self.stream.read(&mut ssl_buf).fuse();
-            let ssl_tx = tun.recv().fuse();
+            let ssl_rx = self.stream.next().fuse();
+            let ssl_tx = tun.pop().fuse();
+            let ka_tick = ka_ticker.tick().fuse();
+            let shutdown = shutdown.recv().fuse();
+            let kicked = kick.recv().fuse();
 
-            pin_mut!(ssl_rx, ssl_tx);
+            pin_mut!(ssl_rx, ssl_tx, ka_tick, shutdown, kicked);
             select! {
                 res  = ssl_rx => {
-                    let n = res.unwrap();
-                    if 0 == n {
-                        break;
-                    } else {
-                        debug!("Recv {:#04x?} from client", n);
-                        let _ = self.router
-                            .send(RouteMsg::Forwarding(TunPacket::new(ssl_buf[..n].to_vec())))
-                            .await;
+                    match res {
+                        Some(Ok(Message::Data(pkt))) => {
+                            debug!("Recv {:#04x?} from client", pkt.len());
+                            if !self.spoof.check(&pkt) {
+                                continue;
+                            }
+                            let _ = self.forward.send((pkt, self.policy.clone())).await;
+                        }
+                        Some(Ok(Message::Ping(seq))) => {
+                            let _ = self.stream.send(Message::Pong(seq)).await;
+                        }
+                        Some(Ok(Message::Pong(seq))) => keepalive.pong(seq),
+                        Some(Ok(Message::Disconnect(reason))) => {
+                            info!(
+                                "Session {}({}) disconnected: {}",
+                                self.name,
+                                self.client_ip,
+                                action::disconnect_reason(reason)
+                            );
+                            self.disconnect = Some(reason);
+                            break;
+                        }
+                        Some(Ok(msg)) => {
+                            warn!("Unexpected message {:?} from client", msg);
+                        }
+                        Some(Err(e)) => return self.reject(action::ERR_PROTOCOL, e.to_string()).await,
+                        None => break,
+                    }
+                },
+
+                _ = ka_tick => {
+                    match keepalive.ping().map_err(|e| e.to_string()) {
+                        Ok(Some(ping)) => {
+                            let _ = self.stream.send(ping).await;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            warn!("Session {}({}): {}", self.name, self.client_ip, e);
+                            self.disconnect(action::DISCONNECT_IDLE_TIMEOUT).await;
+                            return Err(e.into());
+                        }
                     }
                 },
 
+                res = shutdown => {
+                    self.disconnect(res.unwrap_or(action::DISCONNECT_SHUTDOWN)).await;
+                    break;
+                },
+
+                res = kicked => {
+                    self.disconnect(res.unwrap_or(action::DISCONNECT_KICKED)).await;
+                    break;
+                },
+
                 res = ssl_tx => {
                     if let Some(pkt) = res {
-                        debug!("Write {:#04x?} to client", pkt.get_bytes().len());
-                        let _ = self.stream.write_all(pkt.get_bytes()).await;
+                        debug!("Write {:#04x?} to client", pkt.len());
+                        let _ = self
+                            .stream
+                            .send(Message::Data(pkt))
+                            .await;
                     }
                 }
             }
@@ -143,9 +317,121 @@ impl SessionInner {
 impl Drop for SessionInner {
     fn drop(&mut self) {
         info!("Session {}({}) ends", self.name, self.client_ip);
-        ippool::release_client_ip(&self.client_ip).unwrap();
-        let _ = executor::block_on(self.router.send(RouteMsg::DelRoute(self.client_ip.clone())));
+        let spoofed = self.spoof.dropped();
+        if spoofed != 0 {
+            warn!(
+                "Session {}({}): {} packets with spoofed sources dropped",
+                self.name, self.client_ip, spoofed
+            );
+        }
+        let dropped = self.policy.dropped();
+        if dropped != 0 {
+            warn!(
+                "Session {}({}): {} packets to forbidden destinations dropped",
+                self.name, self.client_ip, dropped
+            );
+        }
+        self.queue.close();
+        let overflow = self.queue.overflow();
+        if overflow != 0 {
+            warn!(
+                "Session {}({}): {} packets dropped on a full queue",
+                self.name, self.client_ip, overflow
+            );
+        }
+        for subnet in self.sites.withdraw(self.id) {
+            let _ = executor::block_on(self.router.send(RouteMsg::DelRoute(subnet.to_string())));
+            if let Err(e) = del_kernel_route(&subnet, &self.server_ip, &self.tun_name) {
+                warn!("Session {}: {}", self.name, e);
+            }
+        }
+
+        // a client shutting down is not coming back
+        let resumable = self.capabilities & action::CAP_RESUME != 0
+            && self.disconnect != Some(action::DISCONNECT_SHUTDOWN);
+        match self.resume.close(&self.token, self.id, resumable) {
+            Closed::Parked => {
+                info!("Keep {} for {} to resume", self.client_ip, self.name);
+            }
+            Closed::Released => {
+                for ip in self.addresses() {
+                    if let Err(e) = ippool::release_client_ip(&ip) {
+                        error!("Release {} failed: {}", ip, e);
+                    }
+                }
+            }
+            // the route belongs to the new session
+            Closed::Replaced => return,
+        }
+        for ip in self.addresses() {
+            let _ = executor::block_on(self.router.send(RouteMsg::DelRoute(ip)));
+        }
+    }
+}
+
+// `route add|del` of a subnet behind a client into the tun
+async fn kernel_route(
+    action: &str,
+    subnet: &Prefix,
+    server_ip: &str,
+    tun_name: &str,
+) -> AsyncReturn<()> {
+    if subnet.is_ipv6() {
+        return match action {
+            "add" => add_route6(tun_name, &subnet.to_string()).await,
+            _ => del_route6(tun_name, &subnet.to_string()).await,
+        };
+    }
+    info!("route {} -net {} gw {}", action, subnet, server_ip);
+    let _ = Command::new("route")
+        .arg(action)
+        .arg("-net")
+        .arg(subnet.to_string())
+        .arg("gw")
+        .arg(server_ip)
+        .output()
+        .await?;
+    Ok(())
+}
+
+// `route del` from `Drop`, where nothing can be awaited
+fn del_kernel_route(subnet: &Prefix, server_ip: &str, tun_name: &str) -> AsyncReturn<()> {
+    if subnet.is_ipv6() {
+        return del_route6_blocking(tun_name, &subnet.to_string());
     }
+    info!("route del -net {} gw {}", subnet, server_ip);
+    let _ = std::process::Command::new("route")
+        .arg("del")
+        .arg("-net")
+        .arg(subnet.to_string())
+        .arg("gw")
+        .arg(server_ip)
+        .output()?;
+    Ok(())
+}
+
+// the addresses of a new session of `name` in `pool`
+fn allocate(pool: &str, name: &str) -> AsyncReturn<(String, Option<String>)> {
+    let ip = match config::get_static_ips().remove(name) {
+        Some(ip) => ip,
+        None => ippool::generate_client_ip(pool, name)?,
+    };
+    match ippool::generate_client_ip6(pool, name) {
+        Ok(ip6) => Ok((ip, ip6)),
+        Err(e) => {
+            let _ = ippool::release_client_ip(&ip);
+            Err(e)
+        }
+    }
+}
+
+pub async fn send_error(stream: &mut TunnelStream, code: u16, reason: &str) {
+    let _ = stream
+        .send(Message::Error {
+            code,
+            message: reason.to_string(),
+        })
+        .await;
 }
 
 pub struct Session(SessionInner);
@@ -159,8 +445,13 @@ impl Session {
 pub struct SessionBuilder {
     name: String,
     server_ip: String,
-    stream: Option<BufReader<SslStream<TcpStream>>>,
-    router: Option<mpsc::Sender<RouteMsg>>,
+    tun_name: String,
+    pool: Option<PoolConfig>,
+    stream: Option<TunnelStream>,
+    router: Option<RouterHandle>,
+    resume: Option<ResumeTable>,
+    sites: Option<SiteTable>,
+    shutdown: Option<broadcast::Receiver<u16>>,
 }
 
 impl Default for SessionBuilder {
@@ -168,9 +459,43 @@ impl Default for SessionBuilder {
         SessionBuilder {
             name: "".to_string(),
             server_ip: "".to_string(),
+            tun_name: "".to_string(),
+            pool: None,
             stream: None,
             router: None,
+            resume: None,
+            sites: None,
+            shutdown: None,
+        }
+    }
+}
+
+// the first CONFIG of the client, with the negotiated version and capabilities
+async fn read_request(stream: &mut TunnelStream) -> Result<(u16, u32, String), (u16, String)> {
+    let msg = match stream.next().await {
+        Some(Ok(msg)) => msg,
+        Some(Err(e)) => return Err((action::ERR_PROTOCOL, e.to_string())),
+        None => {
+            return Err((
+                action::ERR_PROTOCOL,
+                "Connection closed during handshake".into(),
+            ))
+        }
+    };
+    match msg {
+        Message::ConfigRequest {
+            version,
+            capabilities,
+            token,
+        } => {
+            let (version, capabilities) =
+                action::negotiate(version, capabilities).map_err(|e| (action::ERR_VERSION, e))?;
+            Ok((version, capabilities, token))
         }
+        msg => Err((
+            action::ERR_PROTOCOL,
+            format!("Unexpected message {:?} during handshake", msg),
+        )),
     }
 }
 
@@ -189,30 +514,161 @@ impl SessionBuilder {
         self
     }
 
-    pub fn stream(mut self, stream: BufReader<SslStream<TcpStream>>) -> Self {
+    // the tun of the server, subnets behind the client are routed into it
+    pub fn tun_name(mut self, name: &str) -> Self {
+        self.tun_name = name.to_string();
+        self
+    }
+
+    // the pool the client gets its addresses and routes from
+    pub fn pool(mut self, pool: PoolConfig) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    pub fn stream(mut self, stream: TunnelStream) -> Self {
         self.stream = Some(stream);
         self
     }
 
-    pub fn router(mut self, router: mpsc::Sender<RouteMsg>) -> Self {
+    pub fn router(mut self, router: RouterHandle) -> Self {
         self.router = Some(router);
         self
     }
 
-    pub fn build(self) -> Session {
+    pub fn resume(mut self, resume: ResumeTable) -> Self {
+        self.resume = Some(resume);
+        self
+    }
+
+    pub fn sites(mut self, sites: SiteTable) -> Self {
+        self.sites = Some(sites);
+        self
+    }
+
+    // carries the DISCONNECT reason when the server wants the session gone
+    pub fn shutdown(mut self, shutdown: broadcast::Receiver<u16>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    // reads the CONFIG of the client to decide which ip it gets,
+    // the client is told why if no session can be built for it
+    pub async fn build(self) -> AsyncReturn<Session> {
         let name = self.name;
-        let client_ip = ippool::generate_client_ip().unwrap();
         let server_ip = self.server_ip;
-        let stream = self.stream.unwrap_or_else(|| panic!("No stream"));
+        let pool = self.pool.unwrap_or_else(|| panic!("No pool"));
+        let mut stream = self.stream.unwrap_or_else(|| panic!("No stream"));
         let router = self.router.unwrap_or_else(|| panic!("No router"));
+        let resume = self.resume.unwrap_or_else(|| panic!("No resume table"));
+        let sites = self.sites.unwrap_or_else(|| panic!("No site table"));
+
+        info!("Connection start");
+        let (version, capabilities, token) = match read_request(&mut stream).await {
+            Ok(request) => request,
+            Err((code, e)) => {
+                error!("Client session \"{}\": {}", name, e);
+                send_error(&mut stream, code, &e).await;
+                return Err(e.into());
+            }
+        };
+        info!(
+            "Client session \"{}\": protocol version {}, capabilities {:#x}",
+            name, version, capabilities
+        );
+
+        let (kick_addr, kick) = mpsc::channel(1);
+        let resumed = if capabilities & action::CAP_RESUME != 0 && !token.is_empty() {
+            resume
+                .resume(&token, &name, kick_addr.clone())
+                .map_err(|e| e.to_string())
+        } else {
+            Ok(None)
+        };
+        let allocated = match resumed {
+            Ok(Some(registration)) => {
+                info!("Client session \"{}\" resumes {}", name, registration.ip);
+                Ok(registration)
+            }
+            Ok(None) => allocate(&pool.name, &name)
+                .and_then(|(client_ip, client_ip6)| {
+                    match resume.open(&name, &client_ip, client_ip6.clone(), kick_addr) {
+                        Ok(registration) => Ok(registration),
+                        Err(e) => {
+                            for ip in std::iter::once(&client_ip).chain(&client_ip6) {
+                                let _ = ippool::release_client_ip(ip);
+                            }
+                            Err(e)
+                        }
+                    }
+                })
+                .map_err(|e| (action::ERR_POOL_EXHAUSTED, e.to_string())),
+            Err(e) => Err((action::ERR_INTERNAL, e)),
+        };
+        let Registration {
+            ip: client_ip,
+            ip6: client_ip6,
+            token,
+            id,
+            orphans,
+        } = match allocated {
+            Ok(registration) => registration,
+            Err((code, e)) => {
+                error!("Client session \"{}\": {}", name, e);
+                send_error(&mut stream, code, &e).await;
+                return Err(e.into());
+            }
+        };
+        // a replaced session leaves the route of its static ip to this one,
+        // the route of its ipv6 address goes before the address is free again
+        for ip in orphans {
+            let _ = router.control.send(RouteMsg::DelRoute(ip.clone())).await;
+            if let Err(e) = ippool::release_client_ip(&ip) {
+                error!("Release {} failed: {}", ip, e);
+            }
+        }
 
-        info!("Client session \"{}\" start", name);
-        Session(SessionInner {
+        info!("Client session \"{}\" start in pool {}", name, pool.name);
+        // with the subnets behind the other clients
+        let mut routes = pool.client_routes.clone();
+        routes.extend(sites.routes(id));
+        let policy = Arc::new(Policy::new(&name, &pool, &routes));
+        let queue = PacketQueue::new(
+            config::get_session_queue() as usize,
+            DropPolicy::parse(&config::get_queue_drop()).unwrap(),
+        );
+        let delegated = config::get_delegated_subnets()
+            .remove(&name)
+            .unwrap_or_default();
+        // the subnets the client may announce are sources too
+        let sources: Vec<String> = std::iter::once(&client_ip)
+            .chain(&client_ip6)
+            .chain(&delegated)
+            .cloned()
+            .collect();
+        let spoof = SpoofGuard::new(&name, &sources);
+        Ok(Session(SessionInner {
+            id,
             name,
             client_ip,
+            client_ip6,
             server_ip,
+            routes,
+            policy,
+            queue,
+            sites,
+            tun_name: self.tun_name,
+            spoof,
+            version,
+            capabilities,
+            token,
+            disconnect: None,
             stream,
-            router,
-        })
+            router: router.control.clone(),
+            forward: router.worker(id),
+            resume,
+            shutdown: self.shutdown,
+            kick: Some(kick),
+        }))
     }
 }
@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::tunnel::prefix::Prefix;
+
+struct Site {
+    name: String,
+    subnets: Vec<Prefix>,
+}
+
+// subnets announced by site-to-site clients, by session id
+pub struct SiteTable(Arc<Mutex<HashMap<u64, Site>>>);
+
+impl SiteTable {
+    pub fn new() -> SiteTable {
+        SiteTable(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    // puts the subnets behind session `id` of `name`, once per session,
+    // a subnet overlapping one of another client stays with it.
+    // Returns the subnets older sessions of the same client give up
+    // for good, their routes are to be removed
+    pub fn announce(&self, id: u64, name: &str, subnets: &[Prefix]) -> Result<Vec<Prefix>, String> {
+        let mut sites = self.0.lock().unwrap();
+        if sites.contains_key(&id) {
+            return Err("Subnets are announced once".to_string());
+        }
+        let overlaps = |a: &Prefix, b: &Prefix| a.covers(b) || b.covers(a);
+        for site in sites.values() {
+            if site.name == name {
+                continue;
+            }
+            for subnet in subnets {
+                if let Some(owned) = site.subnets.iter().find(|owned| overlaps(owned, subnet)) {
+                    return Err(format!("{} overlaps {} of {}", subnet, owned, site.name));
+                }
+            }
+        }
+        let mut stale = vec![];
+        for site in sites.values_mut().filter(|site| site.name == name) {
+            site.subnets.retain(|owned| {
+                if !subnets.iter().any(|subnet| overlaps(owned, subnet)) {
+                    return true;
+                }
+                // the same subnet is simply routed to the new session
+                if !subnets.contains(owned) {
+                    stale.push(*owned);
+                }
+                false
+            });
+        }
+        sites.insert(
+            id,
+            Site {
+                name: name.to_string(),
+                subnets: subnets.to_vec(),
+            },
+        );
+        Ok(stale)
+    }
+
+    // the subnets session `id` still has when it ends
+    pub fn withdraw(&self, id: u64) -> Vec<Prefix> {
+        let mut sites = self.0.lock().unwrap();
+        sites
+            .remove(&id)
+            .map(|site| site.subnets)
+            .unwrap_or_default()
+    }
+
+    // the subnets behind all sessions but `id`, to be pushed to its client
+    pub fn routes(&self, id: u64) -> Vec<String> {
+        let sites = self.0.lock().unwrap();
+        sites
+            .iter()
+            .filter(|(other, _)| **other != id)
+            .flat_map(|(_, site)| site.subnets.iter().map(|subnet| subnet.to_string()))
+            .collect()
+    }
+}
+
+impl Clone for SiteTable {
+    fn clone(&self) -> SiteTable {
+        SiteTable(Arc::clone(&self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefixes(subnets: &[&str]) -> Vec<Prefix> {
+        subnets
+            .iter()
+            .map(|subnet| Prefix::parse(subnet).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn announce_once() {
+        let sites = SiteTable::new();
+        assert_eq!(
+            sites.announce(1, "a", &prefixes(&["10.1.0.0/16"])),
+            Ok(vec![])
+        );
+        assert!(sites.announce(1, "a", &prefixes(&["10.2.0.0/16"])).is_err());
+        assert_eq!(sites.withdraw(1), prefixes(&["10.1.0.0/16"]));
+        assert!(sites.withdraw(1).is_empty());
+    }
+
+    #[test]
+    fn overlap_with_another_client() {
+        let sites = SiteTable::new();
+        sites.announce(1, "a", &prefixes(&["10.1.0.0/16"])).unwrap();
+        // equal, narrower and wider subnets all overlap
+        for subnet in ["10.1.0.0/16", "10.1.2.0/24", "10.0.0.0/8"] {
+            assert!(sites.announce(2, "b", &prefixes(&[subnet])).is_err());
+        }
+        assert_eq!(
+            sites.announce(2, "b", &prefixes(&["10.2.0.0/16"])),
+            Ok(vec![])
+        );
+        assert_eq!(sites.routes(1), vec!["10.2.0.0/16"]);
+        assert_eq!(sites.routes(2), vec!["10.1.0.0/16"]);
+    }
+
+    #[test]
+    fn newer_session_takes_over() {
+        let sites = SiteTable::new();
+        sites
+            .announce(
+                1,
+                "a",
+                &prefixes(&["10.1.0.0/16", "10.2.0.0/16", "10.3.0.0/16"]),
+            )
+            .unwrap();
+        // 10.1 moves, 10.2 narrows, 10.3 stays with the old session
+        let stale = sites
+            .announce(2, "a", &prefixes(&["10.1.0.0/16", "10.2.1.0/24"]))
+            .unwrap();
+        assert_eq!(stale, prefixes(&["10.2.0.0/16"]));
+        assert_eq!(sites.withdraw(1), prefixes(&["10.3.0.0/16"]));
+        assert_eq!(sites.withdraw(2), prefixes(&["10.1.0.0/16", "10.2.1.0/24"]));
+    }
+}
@@ -0,0 +1,73 @@
+use log::*;
+use std::time::{Duration, Instant};
+
+use crate::tunnel::{packet, prefix::Prefix};
+
+// at most one log line per session in this period
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// lets through packets from the addresses of the client
+// and the subnets delegated to it only
+pub struct SpoofGuard {
+    name: String,
+    sources: Vec<Prefix>,
+    dropped: u64,
+    // drops not logged yet
+    unlogged: u64,
+    logged_at: Option<Instant>,
+}
+
+impl SpoofGuard {
+    // `sources` are the addresses and the delegated subnets of the client
+    pub fn new(name: &str, sources: &[String]) -> SpoofGuard {
+        let sources = sources
+            .iter()
+            .filter_map(|source| match Prefix::parse(source) {
+                Ok(prefix) => Some(prefix),
+                Err(e) => {
+                    error!("Session {}: skip source {}: {}", name, source, e);
+                    None
+                }
+            })
+            .collect();
+        SpoofGuard {
+            name: name.to_string(),
+            sources,
+            dropped: 0,
+            unlogged: 0,
+            logged_at: None,
+        }
+    }
+
+    // true if `pkt` may be forwarded
+    pub fn check(&mut self, pkt: &[u8]) -> bool {
+        let src = match packet::source(pkt) {
+            Some(src) => src,
+            // the router drops what is not an ip packet
+            None => return true,
+        };
+        if self.sources.iter().any(|prefix| prefix.contains(&src)) {
+            return true;
+        }
+
+        self.dropped += 1;
+        self.unlogged += 1;
+        let due = match self.logged_at {
+            Some(at) => at.elapsed() >= LOG_INTERVAL,
+            None => true,
+        };
+        if due {
+            warn!(
+                "Session {}: drop {} packets with a spoofed source, last from {}",
+                self.name, self.unlogged, src
+            );
+            self.unlogged = 0;
+            self.logged_at = Some(Instant::now());
+        }
+        false
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
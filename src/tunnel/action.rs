@@ -1,23 +1,275 @@
+use bytes::{Bytes, BytesMut};
+use openssl::sha::sha256;
+
 pub const CONFIG: u8 = 1;
 pub const CONNECT: u8 = 2;
+pub const DATA: u8 = 3;
+pub const CONFIG_RESP: u8 = 4;
+pub const PING: u8 = 5;
+pub const PONG: u8 = 6;
+pub const CONFIG_DATA: u8 = 7;
+pub const ERROR: u8 = 8;
+pub const DISCONNECT: u8 = 9;
+pub const ANNOUNCE: u8 = 10;
 pub const CONFIG_MAGIC: u32 = 0x53435241;
 pub const CONNECT_MAGIC: u32 = 0x53434E43;
 
-pub const CONFIG_BUF: [u8; 7] = [
-    CONFIG,
-    0,
-    4,
-    (CONFIG_MAGIC >> 24) as u8,
-    ((CONFIG_MAGIC & 0x00ff0000) >> 16) as u8,
-    ((CONFIG_MAGIC & 0x0000ff00) >> 8) as u8,
-    (CONFIG_MAGIC & 0x000000ff) as u8,
-];
-pub const CONNECT_BUF: [u8; 7] = [
-    CONNECT,
-    0,
-    4,
-    (CONNECT_MAGIC >> 24) as u8,
-    ((CONNECT_MAGIC & 0x00ff0000) >> 16) as u8,
-    ((CONNECT_MAGIC & 0x0000ff00) >> 8) as u8,
-    (CONNECT_MAGIC & 0x000000ff) as u8,
-];
+// bump PROTOCOL_VERSION on wire changes,
+// and MIN_PROTOCOL_VERSION once the old format is dropped
+pub const PROTOCOL_VERSION: u16 = 2;
+pub const MIN_PROTOCOL_VERSION: u16 = 2;
+// optional features, one bit each
+pub const CAP_KEEPALIVE: u32 = 1 << 0;
+pub const CAP_DISCONNECT: u32 = 1 << 1;
+pub const CAP_RESUME: u32 = 1 << 2;
+pub const CAP_ANNOUNCE: u32 = 1 << 3;
+pub const CAPABILITIES: u32 = CAP_KEEPALIVE | CAP_DISCONNECT | CAP_RESUME | CAP_ANNOUNCE;
+
+// error codes carried by ERROR
+pub const ERR_INTERNAL: u16 = 1;
+pub const ERR_PROTOCOL: u16 = 2;
+pub const ERR_VERSION: u16 = 3;
+pub const ERR_NO_IDENTITY: u16 = 4;
+pub const ERR_POOL_EXHAUSTED: u16 = 5;
+pub const ERR_FORBIDDEN: u16 = 6;
+
+// reasons carried by DISCONNECT
+pub const DISCONNECT_SHUTDOWN: u16 = 1;
+pub const DISCONNECT_KICKED: u16 = 2;
+pub const DISCONNECT_IDLE_TIMEOUT: u16 = 3;
+pub const DISCONNECT_REPLACED: u16 = 4;
+
+// action(1) + length(2)
+pub const HEADER_LEN: usize = 3;
+pub const MAX_PAYLOAD_LEN: usize = u16::MAX as usize;
+// the pushed config is sent in CONFIG_DATA chunks of at most this size
+pub const CONFIG_CHUNK_LEN: usize = 16 * 1024;
+pub const MAX_CONFIG_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    // client -> server, asks for the tunnel config,
+    // with the resumption token of the previous session if any
+    ConfigRequest {
+        version: u16,
+        capabilities: u32,
+        token: String,
+    },
+    // server -> client, the negotiated version and features,
+    // with the length and sha256 of the json config that follows in CONFIG_DATA chunks
+    ConfigResponse {
+        version: u16,
+        capabilities: u32,
+        length: u32,
+        digest: [u8; 32],
+    },
+    ConfigData(Bytes),
+    // both directions, the client asks and the server confirms
+    Connect,
+    // one tunneled ip packet
+    Data(Bytes),
+    // both directions, keepalive probe and its reply carrying the same sequence
+    Ping(u32),
+    Pong(u32),
+    // server -> client, why the server is about to close the connection
+    Error {
+        code: u16,
+        message: String,
+    },
+    // both directions, an orderly close with one of the DISCONNECT_* reasons
+    Disconnect(u16),
+    // client -> server, before CONNECT, the subnets behind the client
+    Announce(Vec<String>),
+}
+
+impl Message {
+    pub fn action(&self) -> u8 {
+        match self {
+            Message::ConfigRequest { .. } => CONFIG,
+            Message::ConfigResponse { .. } => CONFIG_RESP,
+            Message::ConfigData(_) => CONFIG_DATA,
+            Message::Connect => CONNECT,
+            Message::Data(_) => DATA,
+            Message::Ping(_) => PING,
+            Message::Pong(_) => PONG,
+            Message::Error { .. } => ERROR,
+            Message::Disconnect(_) => DISCONNECT,
+            Message::Announce(_) => ANNOUNCE,
+        }
+    }
+}
+
+pub fn disconnect_reason(reason: u16) -> &'static str {
+    match reason {
+        DISCONNECT_SHUTDOWN => "shutdown",
+        DISCONNECT_KICKED => "kicked by admin",
+        DISCONNECT_IDLE_TIMEOUT => "idle timeout",
+        DISCONNECT_REPLACED => "replaced by a newer session",
+        _ => "unknown reason",
+    }
+}
+
+// server side, picks the version and the features both ends support
+pub fn negotiate(version: u16, capabilities: u32) -> Result<(u16, u32), String> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "Incompatible protocol version {}, server supports {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    Ok((version.min(PROTOCOL_VERSION), capabilities & CAPABILITIES))
+}
+
+// client side, checks what the server picked
+pub fn check_negotiated(version: u16, capabilities: u32) -> Result<(), String> {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(format!(
+            "Incompatible protocol version {}, client supports {} to {}",
+            version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    if capabilities & !CAPABILITIES != 0 {
+        return Err(format!(
+            "Server enabled unknown capabilities {:#x}",
+            capabilities & !CAPABILITIES
+        ));
+    }
+    Ok(())
+}
+
+// server side, the CONFIG_RESP and CONFIG_DATA frames pushing `config`
+pub fn config_messages(version: u16, capabilities: u32, config: &str) -> Vec<Message> {
+    let config = Bytes::copy_from_slice(config.as_bytes());
+    let mut msgs = vec![Message::ConfigResponse {
+        version,
+        capabilities,
+        length: config.len() as u32,
+        digest: sha256(&config),
+    }];
+    let mut offset = 0;
+    while offset < config.len() {
+        let end = (offset + CONFIG_CHUNK_LEN).min(config.len());
+        msgs.push(Message::ConfigData(config.slice(offset..end)));
+        offset = end;
+    }
+    msgs
+}
+
+// client side, collects the CONFIG_DATA chunks announced by a CONFIG_RESP
+pub struct ConfigAssembler {
+    length: usize,
+    digest: [u8; 32],
+    buf: BytesMut,
+}
+
+impl ConfigAssembler {
+    pub fn new(length: u32, digest: [u8; 32]) -> Result<Self, String> {
+        let length = length as usize;
+        if length == 0 {
+            return Err("Empty config".to_string());
+        }
+        if length > MAX_CONFIG_LEN {
+            return Err(format!("Config too large: {} bytes", length));
+        }
+        Ok(ConfigAssembler {
+            length,
+            digest,
+            buf: BytesMut::with_capacity(length),
+        })
+    }
+
+    // the whole config once the last chunk arrives
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<String>, String> {
+        if self.buf.len() + chunk.len() > self.length {
+            return Err(format!(
+                "Config overflow, expect {} bytes, got {}",
+                self.length,
+                self.buf.len() + chunk.len()
+            ));
+        }
+        self.buf.extend_from_slice(chunk);
+        if self.buf.len() < self.length {
+            return Ok(None);
+        }
+        if sha256(&self.buf) != self.digest {
+            return Err("Config digest mismatch".to_string());
+        }
+        String::from_utf8(self.buf.to_vec())
+            .map(Some)
+            .map_err(|e| format!("Config is not utf8: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // feeds the CONFIG_DATA chunks of `msgs` to an assembler for their CONFIG_RESP
+    fn assemble(msgs: Vec<Message>) -> Result<Option<String>, String> {
+        let mut msgs = msgs.into_iter();
+        let mut assembler = match msgs.next() {
+            Some(Message::ConfigResponse { length, digest, .. }) => {
+                ConfigAssembler::new(length, digest)?
+            }
+            msg => panic!("Expect a config response, got {:?}", msg),
+        };
+        let mut config = None;
+        for msg in msgs {
+            match msg {
+                Message::ConfigData(chunk) => config = assembler.push(&chunk)?,
+                msg => panic!("Expect config data, got {:?}", msg),
+            }
+        }
+        Ok(config)
+    }
+
+    #[test]
+    fn config_in_chunks() {
+        let config = "x".repeat(CONFIG_CHUNK_LEN * 2 + 1);
+        let msgs = config_messages(PROTOCOL_VERSION, CAPABILITIES, &config);
+        assert_eq!(msgs.len(), 4);
+        assert_eq!(assemble(msgs), Ok(Some(config)));
+    }
+
+    #[test]
+    fn config_of_one_chunk() {
+        let config = r#"{"routes": []}"#;
+        let msgs = config_messages(PROTOCOL_VERSION, CAPABILITIES, config);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(assemble(msgs), Ok(Some(config.to_string())));
+    }
+
+    #[test]
+    fn config_incomplete() {
+        let mut assembler = ConfigAssembler::new(4, sha256(b"abcd")).unwrap();
+        assert_eq!(assembler.push(b"ab"), Ok(None));
+        assert_eq!(assembler.push(b"cd"), Ok(Some("abcd".to_string())));
+    }
+
+    #[test]
+    fn config_overflow() {
+        let mut assembler = ConfigAssembler::new(4, sha256(b"abcd")).unwrap();
+        assert_eq!(assembler.push(b"abc"), Ok(None));
+        assert!(assembler.push(b"de").is_err());
+    }
+
+    #[test]
+    fn config_digest_mismatch() {
+        let mut assembler = ConfigAssembler::new(4, sha256(b"abcd")).unwrap();
+        assert!(assembler.push(b"abce").is_err());
+    }
+
+    #[test]
+    fn config_not_utf8() {
+        let config = [0xff, 0xfe];
+        let mut assembler = ConfigAssembler::new(2, sha256(&config)).unwrap();
+        assert!(assembler.push(&config).is_err());
+    }
+
+    #[test]
+    fn config_length() {
+        assert!(ConfigAssembler::new(0, [0; 32]).is_err());
+        assert!(ConfigAssembler::new(MAX_CONFIG_LEN as u32 + 1, [0; 32]).is_err());
+        assert!(ConfigAssembler::new(MAX_CONFIG_LEN as u32, [0; 32]).is_ok());
+    }
+}
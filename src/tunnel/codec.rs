@@ -0,0 +1,347 @@
+use super::action::{self, Message};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BufMut, BytesMut};
+use std::io;
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+// every frame on the wire is
+// | action: u8 | length: u16 | payload: [u8; length] |
+#[derive(Default)]
+pub struct MessageCodec;
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        MessageCodec
+    }
+}
+
+fn invalid_data<T: Into<Box<dyn std::error::Error + Send + Sync>>>(e: T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn check_magic(name: &str, payload: &[u8], magic: u32) -> io::Result<()> {
+    if payload.len() < 4 {
+        return Err(invalid_data(format!(
+            "Invalid {} length {}",
+            name,
+            payload.len()
+        )));
+    }
+    let got = BigEndian::read_u32(payload);
+    if got != magic {
+        return Err(invalid_data(format!(
+            "Invalid {} magic, expect {:x}, got {:x}",
+            name, magic, got
+        )));
+    }
+    Ok(())
+}
+
+fn check_len(name: &str, payload: &[u8], len: usize) -> io::Result<()> {
+    if payload.len() < len {
+        return Err(invalid_data(format!(
+            "Invalid {} length {}, expect at least {}",
+            name,
+            payload.len(),
+            len
+        )));
+    }
+    Ok(())
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        if src.len() < action::HEADER_LEN {
+            return Ok(None);
+        }
+        let len = BigEndian::read_u16(&src[1..action::HEADER_LEN]) as usize;
+        if src.len() < action::HEADER_LEN + len {
+            src.reserve(action::HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+
+        let act = src.get_u8();
+        src.advance(action::HEADER_LEN - 1);
+        let payload = src.split_to(len).freeze();
+
+        let msg = match act {
+            action::CONFIG => {
+                // | magic: u32 | version: u16 | capabilities: u32 | token: utf8 |
+                // peers before versioning only send the magic, take them as version 0
+                check_magic("config", &payload, action::CONFIG_MAGIC)?;
+                if payload.len() == 4 {
+                    Message::ConfigRequest {
+                        version: 0,
+                        capabilities: 0,
+                        token: String::new(),
+                    }
+                } else {
+                    check_len("config", &payload, 10)?;
+                    Message::ConfigRequest {
+                        version: BigEndian::read_u16(&payload[4..6]),
+                        capabilities: BigEndian::read_u32(&payload[6..10]),
+                        token: String::from_utf8(payload[10..].to_vec()).map_err(invalid_data)?,
+                    }
+                }
+            }
+            action::CONFIG_RESP => {
+                // | version: u16 | capabilities: u32 | length: u32 | sha256: [u8; 32] |
+                check_len("config response", &payload, 42)?;
+                let mut digest = [0u8; 32];
+                digest.copy_from_slice(&payload[10..42]);
+                Message::ConfigResponse {
+                    version: BigEndian::read_u16(&payload[0..2]),
+                    capabilities: BigEndian::read_u32(&payload[2..6]),
+                    length: BigEndian::read_u32(&payload[6..10]),
+                    digest,
+                }
+            }
+            action::CONFIG_DATA => Message::ConfigData(payload),
+            action::CONNECT => {
+                check_magic("connect", &payload, action::CONNECT_MAGIC)?;
+                Message::Connect
+            }
+            action::DATA => Message::Data(payload),
+            action::PING => {
+                check_len("ping", &payload, 4)?;
+                Message::Ping(BigEndian::read_u32(&payload))
+            }
+            action::PONG => {
+                check_len("pong", &payload, 4)?;
+                Message::Pong(BigEndian::read_u32(&payload))
+            }
+            action::ERROR => {
+                // | code: u16 | message: utf8 |
+                check_len("error", &payload, 2)?;
+                Message::Error {
+                    code: BigEndian::read_u16(&payload[0..2]),
+                    message: String::from_utf8_lossy(&payload[2..]).into_owned(),
+                }
+            }
+            action::DISCONNECT => {
+                check_len("disconnect", &payload, 2)?;
+                Message::Disconnect(BigEndian::read_u16(&payload))
+            }
+            action::ANNOUNCE => {
+                // | subnets: utf8, comma separated |
+                let subnets = std::str::from_utf8(&payload).map_err(invalid_data)?;
+                Message::Announce(
+                    subnets
+                        .split(',')
+                        .filter(|subnet| !subnet.is_empty())
+                        .map(|subnet| subnet.to_string())
+                        .collect(),
+                )
+            }
+            x => return Err(invalid_data(format!("Unknown action {}", x))),
+        };
+        Ok(Some(msg))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: Message, dst: &mut BytesMut) -> io::Result<()> {
+        let act = msg.action();
+        let payload = match msg {
+            Message::ConfigRequest {
+                version,
+                capabilities,
+                token,
+            } => {
+                let mut payload = BytesMut::with_capacity(10 + token.len());
+                payload.put_u32(action::CONFIG_MAGIC);
+                payload.put_u16(version);
+                payload.put_u32(capabilities);
+                payload.put(token.as_bytes());
+                payload.freeze()
+            }
+            Message::ConfigResponse {
+                version,
+                capabilities,
+                length,
+                digest,
+            } => {
+                let mut payload = BytesMut::with_capacity(42);
+                payload.put_u16(version);
+                payload.put_u32(capabilities);
+                payload.put_u32(length);
+                payload.put(&digest[..]);
+                payload.freeze()
+            }
+            Message::ConfigData(chunk) => chunk,
+            Message::Connect => action::CONNECT_MAGIC.to_be_bytes().to_vec().into(),
+            Message::Data(pkt) => pkt,
+            Message::Ping(seq) | Message::Pong(seq) => seq.to_be_bytes().to_vec().into(),
+            Message::Disconnect(reason) => reason.to_be_bytes().to_vec().into(),
+            Message::Announce(subnets) => subnets.join(",").into_bytes().into(),
+            Message::Error { code, message } => {
+                let mut payload = BytesMut::with_capacity(2 + message.len());
+                payload.put_u16(code);
+                payload.put(message.as_bytes());
+                payload.freeze()
+            }
+        };
+        if payload.len() > action::MAX_PAYLOAD_LEN {
+            return Err(invalid_data(format!(
+                "Payload too large: {} bytes",
+                payload.len()
+            )));
+        }
+
+        dst.reserve(action::HEADER_LEN + payload.len());
+        dst.put_u8(act);
+        dst.put_u16(payload.len() as u16);
+        dst.put(payload);
+        Ok(())
+    }
+}
+
+pub type TunnelStream = Framed<SslStream<TcpStream>, MessageCodec>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn encode(msg: Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        MessageCodec::new().encode(msg, &mut buf).unwrap();
+        buf
+    }
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::ConfigRequest {
+                version: action::PROTOCOL_VERSION,
+                capabilities: action::CAPABILITIES,
+                token: "token".to_string(),
+            },
+            Message::ConfigResponse {
+                version: action::PROTOCOL_VERSION,
+                capabilities: action::CAP_KEEPALIVE,
+                length: 1234,
+                digest: [7; 32],
+            },
+            Message::ConfigData(Bytes::from_static(b"{}")),
+            Message::Connect,
+            Message::Data(Bytes::from_static(&[0x45, 0, 0, 20])),
+            Message::Ping(1),
+            Message::Pong(u32::MAX),
+            Message::Error {
+                code: action::ERR_FORBIDDEN,
+                message: "forbidden".to_string(),
+            },
+            Message::Disconnect(action::DISCONNECT_SHUTDOWN),
+            Message::Announce(vec!["10.1.0.0/16".to_string(), "fd00::/64".to_string()]),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        for msg in messages() {
+            let mut buf = encode(msg.clone());
+            assert_eq!(MessageCodec::new().decode(&mut buf).unwrap(), Some(msg));
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn partial_frame() {
+        let frame = encode(Message::Ping(42));
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        for (i, byte) in frame.iter().enumerate() {
+            assert_eq!(codec.decode(&mut buf).unwrap(), None, "after {} bytes", i);
+            buf.put_u8(*byte);
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Ping(42)));
+    }
+
+    #[test]
+    fn merged_and_split_reads() {
+        let mut stream = BytesMut::new();
+        for msg in messages() {
+            stream.extend_from_slice(&encode(msg));
+        }
+        // the stream arrives in reads cutting through the frames
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        let mut got = vec![];
+        for chunk in stream.chunks(5) {
+            buf.extend_from_slice(chunk);
+            while let Some(msg) = codec.decode(&mut buf).unwrap() {
+                got.push(msg);
+            }
+        }
+        assert_eq!(got, messages());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(action::CONNECT);
+        buf.put_u16(4);
+        buf.put_u32(action::CONFIG_MAGIC);
+        let e = MessageCodec::new().decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_action() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(0xff);
+        buf.put_u16(0);
+        assert!(MessageCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn short_payload() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(action::PING);
+        buf.put_u16(2);
+        buf.put_u16(1);
+        assert!(MessageCodec::new().decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn legacy_config() {
+        // peers before versioning only send the magic
+        let mut buf = BytesMut::new();
+        buf.put_u8(action::CONFIG);
+        buf.put_u16(4);
+        buf.put_u32(action::CONFIG_MAGIC);
+        assert_eq!(
+            MessageCodec::new().decode(&mut buf).unwrap(),
+            Some(Message::ConfigRequest {
+                version: 0,
+                capabilities: 0,
+                token: String::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn oversized_payload() {
+        let mut buf = BytesMut::new();
+        let pkt = Bytes::from(vec![0; action::MAX_PAYLOAD_LEN + 1]);
+        assert!(MessageCodec::new()
+            .encode(Message::Data(pkt), &mut buf)
+            .is_err());
+        assert!(buf.is_empty());
+
+        let pkt = Bytes::from(vec![0; action::MAX_PAYLOAD_LEN]);
+        let mut buf = encode(Message::Data(pkt.clone()));
+        assert_eq!(
+            MessageCodec::new().decode(&mut buf).unwrap(),
+            Some(Message::Data(pkt))
+        );
+    }
+}
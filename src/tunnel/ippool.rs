@@ -1,6 +1,21 @@
-use crate::AsyncReturn;
+use super::lease::Leases;
+use crate::{config, AsyncReturn};
 use log::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::{task, time};
+
+// the largest network a pool may cover
+const MIN_MASK: u8 = 8;
+const MAX_MASK: u8 = 30;
+const MAX_MASK6: u8 = 126;
+// an ipv6 prefix is far larger than the clients it serves,
+// only its first hosts are handed out
+const MAX_HOSTS6: u32 = 1 << 20;
+// how often changed leases are written out
+const LEASE_FLUSH: Duration = Duration::from_secs(1);
 
 #[derive(PartialEq)]
 enum IpPooMode {
@@ -8,146 +23,483 @@ enum IpPooMode {
     Network,
 }
 
+// hosts of the network are numbered 1..=size from the network address,
+// a set bit in `used` means the host is handed out or reserved
+struct Allocator {
+    used: Vec<u64>,
+    // never handed out dynamically
+    reserved: HashSet<u32>,
+    // hosts from here on were never handed out
+    next: u32,
+    // released hosts with the time they were released, oldest first,
+    // an entry not matching `released` is stale and skipped
+    free: VecDeque<(u32, Instant)>,
+    // the free hosts that were handed out before, with their release time
+    released: HashMap<u32, Instant>,
+    // the host each client got last, until another client gets it
+    owners: HashMap<String, u32>,
+    owned: HashMap<u32, String>,
+    // how long a released host rests before another client gets it
+    quarantine: Duration,
+}
+
+impl Allocator {
+    fn new(size: u32) -> Allocator {
+        Allocator {
+            used: vec![0; size as usize / 64 + 1],
+            reserved: HashSet::new(),
+            next: 1,
+            free: VecDeque::new(),
+            released: HashMap::new(),
+            owners: HashMap::new(),
+            owned: HashMap::new(),
+            quarantine: Duration::ZERO,
+        }
+    }
+
+    fn is_used(&self, host: u32) -> bool {
+        self.used[host as usize / 64] & (1 << (host % 64)) != 0
+    }
+
+    fn set_used(&mut self, host: u32, used: bool) {
+        if used {
+            self.used[host as usize / 64] |= 1 << (host % 64);
+        } else {
+            self.used[host as usize / 64] &= !(1 << (host % 64));
+        }
+    }
+
+    // marks a free host used, wherever it is
+    fn take(&mut self, host: u32) {
+        self.set_used(host, true);
+        self.released.remove(&host);
+    }
+
+    fn release(&mut self, host: u32) {
+        let now = Instant::now();
+        self.set_used(host, false);
+        self.released.insert(host, now);
+        self.free.push_back((host, now));
+        // hosts taken back by their owners leave stale entries behind,
+        // they go once they outnumber the live ones
+        if self.free.len() > 2 * self.released.len() + 64 {
+            let released = &self.released;
+            self.free
+                .retain(|(host, at)| released.get(host) == Some(at));
+        }
+    }
+
+    // the host `name` had before if nobody took it since,
+    // otherwise the one free for the longest, never handed out hosts first
+    fn alloc(&mut self, name: &str, size: u32) -> Option<u32> {
+        if let Some(&host) = self.owners.get(name) {
+            if !self.is_used(host) {
+                self.take(host);
+                return Some(host);
+            }
+        }
+        let host = self.alloc_fresh(size).or_else(|| self.alloc_released())?;
+        if let Some(old) = self.owned.insert(host, name.to_string()) {
+            self.owners.remove(&old);
+        }
+        if let Some(prev) = self.owners.insert(name.to_string(), host) {
+            self.owned.remove(&prev);
+        }
+        Some(host)
+    }
+
+    fn alloc_fresh(&mut self, size: u32) -> Option<u32> {
+        while self.next <= size {
+            let host = self.next;
+            self.next += 1;
+            if !self.is_used(host) {
+                self.set_used(host, true);
+                return Some(host);
+            }
+        }
+        None
+    }
+
+    fn alloc_released(&mut self) -> Option<u32> {
+        while let Some(&(host, released)) = self.free.front() {
+            if self.released.get(&host) != Some(&released) {
+                self.free.pop_front();
+                continue;
+            }
+            if released.elapsed() < self.quarantine {
+                return None;
+            }
+            self.free.pop_front();
+            self.take(host);
+            return Some(host);
+        }
+        None
+    }
+}
+
 struct IpPoolInner {
     name: String,
-    ips: Mutex<Vec<String>>,
+    // network address in host order, or the only address in host mode
+    net: u128,
+    v6: bool,
+    size: u32,
+    alloc: Mutex<Allocator>,
     mode: IpPooMode,
 }
 
-static mut POOL: Option<&IpPool> = None;
+fn to_num(ip: IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip) as u128,
+        IpAddr::V6(ip) => u128::from(ip),
+    }
+}
+
+fn from_num(num: u128, v6: bool) -> IpAddr {
+    if v6 {
+        Ipv6Addr::from(num).into()
+    } else {
+        Ipv4Addr::from(num as u32).into()
+    }
+}
+
+// the host part of a network with `bits` host bits
+fn host_mask(bits: u8) -> u128 {
+    1u128.checked_shl(bits as u32).map_or(u128::MAX, |n| n - 1)
+}
+
+// `ip` or `net/mask`, ipv4 or ipv6
+fn parse_network(ips: &str) -> AsyncReturn<(IpAddr, Option<u8>)> {
+    let ips: Vec<&str> = ips.split('/').collect();
+    match ips[..] {
+        [ip] => Ok((ip.parse::<IpAddr>()?, None)),
+        [net, mask] => {
+            let net = net.parse::<IpAddr>()?;
+            let mask = mask.parse::<u8>()?;
+            let (bits, max_mask) = match net {
+                IpAddr::V4(_) => (32, MAX_MASK),
+                IpAddr::V6(_) => (128, MAX_MASK6),
+            };
+            if mask > max_mask {
+                return Err(format!("Network mask cannot be larger than {}", max_mask).into());
+            }
+            if to_num(net) & host_mask(bits - mask) != 0 {
+                return Err(format!("{} is not a network address", net).into());
+            }
+            Ok((net, Some(mask)))
+        }
+        _ => Err("Not a valid network address".into()),
+    }
+}
+
+// the ipv4 and the optional ipv6 pool of every client group, by group name
+static mut POOLS: Vec<&IpPool> = Vec::new();
+// with a lease file, clients keep their addresses across sessions and restarts
+static mut LEASES: Option<&Mutex<Leases>> = None;
 
 pub struct IpPool(Arc<IpPoolInner>);
 
-pub fn init(name: &str, ips: &str) -> AsyncReturn<()> {
+fn add_pool(pool: IpPool) -> AsyncReturn<()> {
     unsafe {
-        if POOL.is_some() {
-            panic!("Cannot init twice");
+        for other in POOLS.iter() {
+            if other.0.name == pool.0.name && other.0.v6 == pool.0.v6 {
+                panic!("Cannot init twice");
+            }
+            if other.overlaps(&pool) {
+                return Err(format!("Pool {} overlaps pool {}", pool.0.name, other.0.name).into());
+            }
         }
     }
-    let pool = Box::new(IpPool::new(name, ips)?);
+    pool.set_quarantine(Duration::from_secs(config::get_ip_quarantine() as u64));
+    let pool: &'static IpPool = Box::leak(Box::new(pool));
+
+    let lease_file = config::get_lease_file();
     unsafe {
-        POOL = Some(Box::leak(pool));
+        if LEASES.is_none() && !lease_file.is_empty() {
+            let mut leases = Leases::load(&lease_file, config::get_lease_time() as u64)?;
+            leases.expire();
+            LEASES = Some(Box::leak(Box::new(Mutex::new(leases))));
+        }
+        if let Some(leases) = LEASES {
+            claim_leases(&mut leases.lock().unwrap(), pool);
+        }
+        POOLS.push(pool);
     }
     Ok(())
 }
 
-pub fn generate_client_ip() -> AsyncReturn<String> {
-    unsafe {
-        if POOL.is_none() {
-            panic!("Cannot generate ip before init");
+// the ipv4 pool of the group `name`
+pub fn init(name: &str, ips: &str) -> AsyncReturn<()> {
+    let pool = IpPool::new(name, ips)?;
+    if pool.0.v6 {
+        return Err(format!("Pool {}: {} is not an ipv4 network", name, ips).into());
+    }
+    add_pool(pool)
+}
+
+// the ipv6 pool of the group `name`
+pub fn init6(name: &str, ips: &str) -> AsyncReturn<()> {
+    let pool = IpPool::new(name, ips)?;
+    if !pool.0.v6 {
+        return Err(format!("Pool {}: {} is not an ipv6 network", name, ips).into());
+    }
+    add_pool(pool)
+}
+
+// takes the leased addresses of `pool` out of it,
+// leases on static ips or taken addresses are dropped
+fn claim_leases(leases: &mut Leases, pool: &IpPool) {
+    let static_ips: Vec<String> = config::get_static_ips().into_values().collect();
+    for ip in leases.ips(pool.is_v6()) {
+        if !pool.contains(&ip) {
+            continue;
+        }
+        if static_ips.contains(&ip) || pool.claim(&ip).is_err() {
+            warn!("Pool {}: Drop the lease of {}", pool.0.name, ip);
+            leases.remove(&ip);
         }
     }
-    unsafe { POOL.unwrap().get_ip() }
 }
 
-pub fn release_client_ip(ip: &str) -> AsyncReturn<()> {
+// the address `name` had in `pool` before if it is still leased, a new one otherwise
+fn lease_ip(pool: &IpPool, name: &str) -> AsyncReturn<String> {
+    let leases = match unsafe { LEASES } {
+        Some(leases) if !pool.is_host_mode() => leases,
+        _ => return pool.get_ip(name),
+    };
+    let mut leases = leases.lock().unwrap();
+    for ip in leases.expire() {
+        if let Some(Err(e)) = pool_of(&ip).map(|pool| pool.free_ip(&ip)) {
+            debug!("Reclaim {}: {}", ip, e);
+        }
+    }
+    if let Some(ip) = leases.take(name, |ip| pool.contains(ip)) {
+        info!("Pool {}: {} gets its leased ip {}", pool.0.name, name, ip);
+        return Ok(ip);
+    }
+    let ip = match pool.get_ip(name) {
+        Ok(ip) => ip,
+        // every address is leased, the client gone the longest gives its one up,
+        // still taken in the pool it goes straight to `name`
+        Err(e) => match leases.evict(config::get_ip_quarantine() as u64, |ip| pool.contains(ip)) {
+            Some(ip) => {
+                info!(
+                    "Pool {}: {} gets the released lease {}",
+                    pool.0.name, name, ip
+                );
+                ip
+            }
+            None => return Err(e),
+        },
+    };
+    leases.grant(name, &ip);
+    Ok(ip)
+}
+
+// writes out the leases if they changed, blocks on the file
+pub fn save_leases() {
+    if let Some(leases) = unsafe { LEASES } {
+        let snapshot = leases.lock().unwrap().snapshot();
+        if let Some(snapshot) = snapshot {
+            snapshot.write();
+        }
+    }
+}
+
+// saves the leases now and then, sessions never wait on the file
+pub async fn flush_leases() {
+    let mut interval = time::interval(LEASE_FLUSH);
+    loop {
+        interval.tick().await;
+        let _ = task::spawn_blocking(save_leases).await;
+    }
+}
+
+// the pool `ip` comes from
+fn pool_of(ip: &str) -> Option<&'static IpPool> {
+    unsafe { POOLS.iter().find(|pool| pool.contains(ip)).copied() }
+}
+
+fn find_pool(group: &str, v6: bool) -> Option<&'static IpPool> {
     unsafe {
-        if POOL.is_none() {
-            panic!("Cannot release ip before init");
+        POOLS
+            .iter()
+            .find(|pool| pool.0.name == group && pool.0.v6 == v6)
+            .copied()
+    }
+}
+
+// an address for the client `name` from the pool of `group`
+pub fn generate_client_ip(group: &str, name: &str) -> AsyncReturn<String> {
+    match find_pool(group, false) {
+        Some(pool) => lease_ip(pool, name),
+        None => panic!("Cannot generate ip before init"),
+    }
+}
+
+// None when the group has no ipv6 pool
+pub fn generate_client_ip6(group: &str, name: &str) -> AsyncReturn<Option<String>> {
+    match find_pool(group, true) {
+        Some(pool) => lease_ip(pool, name).map(Some),
+        None => Ok(None),
+    }
+}
+
+pub fn reserve_client_ip(ip: &str) -> AsyncReturn<()> {
+    match pool_of(ip) {
+        Some(pool) => pool.reserve(ip),
+        None => Err(format!("{} is in no pool", ip).into()),
+    }
+}
+
+pub fn release_client_ip(ip: &str) -> AsyncReturn<()> {
+    if let Some(leases) = unsafe { LEASES } {
+        if leases.lock().unwrap().release(ip) {
+            debug!("Keep leased ip {}", ip);
+            return Ok(());
         }
     }
-    unsafe { POOL.unwrap().free_ip(ip) }
+    match pool_of(ip) {
+        Some(pool) => pool.free_ip(ip),
+        None => Err(format!("{} is in no pool", ip).into()),
+    }
 }
 
 impl IpPool {
     pub fn new(name: &str, ips: &str) -> AsyncReturn<IpPool> {
-        let ips: Vec<&str> = ips.split('/').collect();
-        if ips.len() != 2 {
-            let ips = ips.iter().map(|x| x.to_string()).collect::<Vec<String>>();
-            Ok(IpPool(Arc::new(IpPoolInner {
-                name: name.to_string(),
-                ips: Mutex::new(ips),
-                mode: IpPooMode::Host,
-            })))
-        } else {
-            let (net, mask) = (ips[0], ips[1]);
-            let net: Vec<&str> = net.split('.').collect();
-            let mask = mask.parse::<u8>().unwrap();
-            if mask > 30 {
-                return Err("Network mask cannot be larger than 30".into());
+        let (net, mask) = parse_network(ips)?;
+        let v6 = net.is_ipv6();
+        let (size, mode) = match mask {
+            None => (1, IpPooMode::Host),
+            Some(mask) if v6 => {
+                // without the subnet-router anycast address
+                let hosts = host_mask(128 - mask).min(MAX_HOSTS6 as u128);
+                (hosts as u32, IpPooMode::Network)
             }
-            let dig_net: Vec<u8> = net.iter().map(|x| x.parse::<u8>().unwrap()).collect();
-            let dig_net = u32::from_be_bytes(dig_net.try_into().unwrap());
-            let ip_num = (1 << (32 - mask)) as usize;
-
-            let mut ips = Vec::with_capacity(ip_num - 2);
-            for i in 1..ip_num - 1 {
-                let ipe = dig_net + i as u32;
-                let ipe = ipe
-                    .to_be_bytes()
-                    .iter()
-                    .map(|x| x.to_string())
-                    .collect::<Vec<String>>()
-                    .join(".");
-                ips.push(ipe);
+            Some(mask) if mask < MIN_MASK => {
+                return Err(format!("Network mask cannot be smaller than {}", MIN_MASK).into());
             }
-            Ok(IpPool(Arc::new(IpPoolInner {
-                name: name.to_string(),
-                ips: Mutex::new(ips),
-                mode: IpPooMode::Network,
-            })))
-        }
+            // without the network and the broadcast address
+            Some(mask) => ((1u32 << (32 - mask)) - 2, IpPooMode::Network),
+        };
+        let alloc = match mode {
+            IpPooMode::Host => Allocator::new(0),
+            IpPooMode::Network => Allocator::new(size),
+        };
+        Ok(IpPool(Arc::new(IpPoolInner {
+            name: name.to_string(),
+            net: to_num(net),
+            v6,
+            size,
+            alloc: Mutex::new(alloc),
+            mode,
+        })))
     }
 
     pub fn test_new(name: &str, ips: &str) -> AsyncReturn<IpPool> {
-        let ips: Vec<&str> = ips.split('/').collect();
-        if ips.len() != 2 {
-            if ips.len() != 1 {
-                return Err("Not a valid network address".into());
-            }
+        let (net, mask) = parse_network(ips)?;
+        Ok(IpPool(Arc::new(IpPoolInner {
+            name: name.to_string(),
+            net: to_num(net),
+            v6: net.is_ipv6(),
+            size: 0,
+            alloc: Mutex::new(Allocator::new(0)),
+            mode: match mask {
+                None => IpPooMode::Host,
+                Some(_) => IpPooMode::Network,
+            },
+        })))
+    }
 
-            Ok(IpPool(Arc::new(IpPoolInner {
-                name: name.to_string(),
-                ips: Mutex::new(vec![]),
-                mode: IpPooMode::Host,
-            })))
-        } else {
-            let (net, mask) = (ips[0], ips[1]);
-            let net: Vec<&str> = net.split('.').collect();
-            let mask = mask.parse::<u8>().unwrap();
-            if mask > 30 {
-                return Err("Network mask cannot be larger than 30".into());
-            }
-            let dig_net: Vec<u8> = net.iter().map(|x| x.parse::<u8>().unwrap()).collect();
-            let _dig_net = u32::from_be_bytes(dig_net.try_into().unwrap());
-            let _ip_num = (1 << (32 - mask)) as usize;
-            Ok(IpPool(Arc::new(IpPoolInner {
-                name: name.to_string(),
-                ips: Mutex::new(vec![]),
-                mode: IpPooMode::Network,
-            })))
+    // the host number of `ip` within the network
+    fn host_of(&self, ip: &str) -> AsyncReturn<u32> {
+        let addr = ip.parse::<IpAddr>()?;
+        if addr.is_ipv6() != self.0.v6 {
+            return Err(format!("Pool {}: {} is of another family", self.0.name, ip).into());
         }
+        match to_num(addr).checked_sub(self.0.net) {
+            Some(host) if (1..=self.0.size as u128).contains(&host) => Ok(host as u32),
+            _ => Err(format!("Pool {}: {} does not belong to the pool", self.0.name, ip).into()),
+        }
+    }
+
+    fn ip_of(&self, host: u32) -> String {
+        from_num(self.0.net + host as u128, self.0.v6).to_string()
+    }
+
+    pub fn contains(&self, ip: &str) -> bool {
+        self.host_of(ip).is_ok()
+    }
+
+    // the address ranges share an address
+    fn overlaps(&self, other: &IpPool) -> bool {
+        let (a, b) = (&self.0, &other.0);
+        a.v6 == b.v6 && a.net <= b.net + b.size as u128 && b.net <= a.net + a.size as u128
     }
 
-    pub fn get_ip(&self) -> AsyncReturn<String> {
+    pub fn set_quarantine(&self, quarantine: Duration) {
+        self.0.alloc.lock().unwrap().quarantine = quarantine;
+    }
+
+    // an address for the client `name`
+    pub fn get_ip(&self, name: &str) -> AsyncReturn<String> {
         match self.0.mode {
-            IpPooMode::Host => {
-                let ips = self.0.ips.lock().unwrap();
-                Ok(ips[0].clone())
-            }
+            IpPooMode::Host => Ok(from_num(self.0.net, self.0.v6).to_string()),
             IpPooMode::Network => {
-                let mut ips = self.0.ips.lock().unwrap();
-                if ips.len() == 0 {
-                    error!("{} ip pool is empty", self.0.name);
-                    Err(format!("Pool {}: No ip left", self.0.name).into())
-                } else {
-                    let ip = ips.remove(0);
-                    debug!("Pool {}: Get ip {}", self.0.name, ip);
-                    Ok(ip)
+                let host = self.0.alloc.lock().unwrap().alloc(name, self.0.size);
+                match host {
+                    Some(host) => {
+                        let ip = self.ip_of(host);
+                        debug!("Pool {}: Get ip {}", self.0.name, ip);
+                        Ok(ip)
+                    }
+                    None => {
+                        error!("{} ip pool is empty", self.0.name);
+                        Err(format!("Pool {}: No ip left", self.0.name).into())
+                    }
                 }
             }
         }
     }
 
+    // marks `ip` used without handing it out
+    fn claim(&self, ip: &str) -> AsyncReturn<u32> {
+        if self.0.mode == IpPooMode::Host {
+            return Err(format!("Pool {}: Cannot claim in host mode", self.0.name).into());
+        }
+        let host = self.host_of(ip)?;
+        let mut alloc = self.0.alloc.lock().unwrap();
+        if alloc.is_used(host) {
+            return Err(format!("Pool {}: {} is not available", self.0.name, ip).into());
+        }
+        alloc.take(host);
+        Ok(host)
+    }
+
+    pub fn reserve(&self, ip: &str) -> AsyncReturn<()> {
+        let host = self.claim(ip)?;
+        let mut alloc = self.0.alloc.lock().unwrap();
+        alloc.reserved.insert(host);
+        debug!("Pool {}: Reserve ip {}", self.0.name, ip);
+        Ok(())
+    }
+
     pub fn free_ip(&self, ip: &str) -> AsyncReturn<()> {
         match self.0.mode {
             IpPooMode::Host => {
                 // do nothing
             }
             IpPooMode::Network => {
-                let mut ips = self.0.ips.lock().unwrap();
+                let host = self.host_of(ip)?;
+                let mut alloc = self.0.alloc.lock().unwrap();
+                if alloc.reserved.contains(&host) {
+                    // stays reserved
+                    return Ok(());
+                }
+                if !alloc.is_used(host) {
+                    return Err(format!("Pool {}: Double free of ip {}", self.0.name, ip).into());
+                }
                 debug!("Pool {}: Free ip {}", self.0.name, ip);
-                ips.push(ip.to_string());
+                alloc.release(host);
             }
         }
         Ok(())
@@ -157,6 +509,10 @@ impl IpPool {
     pub fn is_host_mode(&self) -> bool {
         self.0.mode == IpPooMode::Host
     }
+
+    pub fn is_v6(&self) -> bool {
+        self.0.v6
+    }
 }
 
 impl Clone for IpPool {
@@ -164,3 +520,161 @@ impl Clone for IpPool {
         IpPool(Arc::clone(&self.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_every_host_once() {
+        // without the network and the broadcast address
+        let pool = IpPool::new("test", "10.0.0.0/30").unwrap();
+        assert_eq!(pool.get_ip("a").unwrap(), "10.0.0.1");
+        assert_eq!(pool.get_ip("b").unwrap(), "10.0.0.2");
+        assert!(pool.get_ip("c").is_err());
+    }
+
+    #[test]
+    fn free_and_double_free() {
+        let pool = IpPool::new("test", "10.0.0.0/30").unwrap();
+        let ip = pool.get_ip("a").unwrap();
+        pool.free_ip(&ip).unwrap();
+        assert!(pool.free_ip(&ip).is_err());
+        assert!(pool.free_ip("10.0.1.1").is_err());
+        assert_eq!(pool.get_ip("b").unwrap(), "10.0.0.2");
+        assert_eq!(pool.get_ip("c").unwrap(), ip);
+    }
+
+    #[test]
+    fn bitmap_across_words() {
+        let pool = IpPool::new("test", "10.0.0.0/24").unwrap();
+        let ips: Vec<String> = (0..254)
+            .map(|i| pool.get_ip(&i.to_string()).unwrap())
+            .collect();
+        assert_eq!(ips[63], "10.0.0.64");
+        assert_eq!(ips[253], "10.0.0.254");
+        assert!(pool.get_ip("full").is_err());
+        pool.free_ip("10.0.0.64").unwrap();
+        assert_eq!(pool.get_ip("again").unwrap(), "10.0.0.64");
+    }
+
+    #[test]
+    fn reserved_stays_out() {
+        let pool = IpPool::new("test", "10.0.0.0/29").unwrap();
+        pool.reserve("10.0.0.1").unwrap();
+        assert!(pool.reserve("10.0.0.1").is_err());
+        assert_eq!(pool.get_ip("a").unwrap(), "10.0.0.2");
+        // freeing a static ip keeps it reserved
+        pool.free_ip("10.0.0.1").unwrap();
+        assert_eq!(pool.get_ip("b").unwrap(), "10.0.0.3");
+    }
+
+    #[test]
+    fn host_mode() {
+        let pool = IpPool::new("test", "10.0.0.1").unwrap();
+        assert!(pool.is_host_mode());
+        assert_eq!(pool.get_ip("a").unwrap(), "10.0.0.1");
+        assert_eq!(pool.get_ip("b").unwrap(), "10.0.0.1");
+        pool.free_ip("10.0.0.1").unwrap();
+    }
+
+    #[test]
+    fn ipv6_pool() {
+        let pool = IpPool::new("test", "fd00::/64").unwrap();
+        assert!(pool.is_v6());
+        assert_eq!(pool.0.size, MAX_HOSTS6);
+        assert_eq!(pool.get_ip("a").unwrap(), "fd00::1");
+        assert!(!pool.contains("10.0.0.1"));
+        assert!(!pool.contains("fd00:0:0:1::1"));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        assert!(IpPool::new("test", "10.0.0.1/24").is_err());
+        assert!(IpPool::new("test", "10.0.0.0/31").is_err());
+        assert!(IpPool::new("test", "10.0.0.0/7").is_err());
+        assert!(IpPool::new("test", "fd00::/127").is_err());
+        assert!(IpPool::new("test", "10.0.0.0/24/8").is_err());
+    }
+
+    #[test]
+    fn returning_client_gets_its_host() {
+        let pool = IpPool::new("test", "10.0.0.0/29").unwrap();
+        let ip = pool.get_ip("a").unwrap();
+        pool.free_ip(&ip).unwrap();
+        pool.get_ip("b").unwrap();
+        assert_eq!(pool.get_ip("a").unwrap(), ip);
+    }
+
+    #[test]
+    fn least_recently_released_first() {
+        let pool = IpPool::new("test", "10.0.0.0/30").unwrap();
+        let a = pool.get_ip("a").unwrap();
+        let b = pool.get_ip("b").unwrap();
+        pool.free_ip(&b).unwrap();
+        pool.free_ip(&a).unwrap();
+        assert_eq!(pool.get_ip("c").unwrap(), b);
+        assert_eq!(pool.get_ip("d").unwrap(), a);
+        assert!(pool.get_ip("e").is_err());
+    }
+
+    #[test]
+    fn stale_free_entries_are_skipped() {
+        let pool = IpPool::new("test", "10.0.0.0/30").unwrap();
+        let a = pool.get_ip("a").unwrap();
+        let b = pool.get_ip("b").unwrap();
+        pool.free_ip(&a).unwrap();
+        pool.free_ip(&b).unwrap();
+        // `a` comes back and leaves again, behind `b` now
+        assert_eq!(pool.get_ip("a").unwrap(), a);
+        pool.free_ip(&a).unwrap();
+        assert_eq!(pool.get_ip("c").unwrap(), b);
+        assert_eq!(pool.get_ip("d").unwrap(), a);
+        assert!(pool.get_ip("e").is_err());
+    }
+
+    #[test]
+    fn stale_free_entries_are_dropped() {
+        let mut alloc = Allocator::new(1);
+        assert_eq!(alloc.alloc("a", 1), Some(1));
+        for _ in 0..1000 {
+            alloc.release(1);
+            assert_eq!(alloc.alloc("a", 1), Some(1));
+        }
+        assert!(alloc.free.len() <= 64 + 1);
+    }
+
+    #[test]
+    fn quarantine() {
+        let pool = IpPool::new("test", "10.0.0.0/30").unwrap();
+        pool.set_quarantine(Duration::from_secs(3600));
+        let a = pool.get_ip("a").unwrap();
+        pool.get_ip("b").unwrap();
+        pool.free_ip(&a).unwrap();
+        assert!(pool.get_ip("c").is_err());
+        // its owner may have it back
+        assert_eq!(pool.get_ip("a").unwrap(), a);
+    }
+
+    #[test]
+    fn owner_forgotten_when_host_taken() {
+        let mut alloc = Allocator::new(1);
+        assert_eq!(alloc.alloc("a", 1), Some(1));
+        alloc.release(1);
+        assert_eq!(alloc.alloc("b", 1), Some(1));
+        assert!(!alloc.owners.contains_key("a"));
+        assert_eq!(alloc.owned.get(&1).map(String::as_str), Some("b"));
+        alloc.release(1);
+        assert_eq!(alloc.alloc("b", 1), Some(1));
+        assert_eq!(alloc.owners.len(), 1);
+    }
+
+    #[test]
+    fn overlapping_pools() {
+        let a = IpPool::new("a", "10.0.0.0/24").unwrap();
+        let b = IpPool::new("b", "10.0.0.128/25").unwrap();
+        let c = IpPool::new("c", "10.0.1.0/24").unwrap();
+        assert!(a.overlaps(&b) && b.overlaps(&a));
+        assert!(!a.overlaps(&c));
+    }
+}
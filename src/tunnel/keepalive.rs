@@ -0,0 +1,51 @@
+use super::action::{self, Message};
+use crate::{config, AsyncReturn};
+use log::*;
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval};
+
+// sends a PING every interval and
+// declares the peer dead after `retries` PINGs without a PONG
+pub struct Keepalive {
+    enabled: bool,
+    retries: u32,
+    missed: u32,
+    seq: u32,
+    interval: Duration,
+}
+
+impl Keepalive {
+    pub fn new(capabilities: u32) -> Self {
+        let interval = Duration::from_secs(config::get_keepalive_interval() as u64);
+        Keepalive {
+            enabled: capabilities & action::CAP_KEEPALIVE != 0,
+            retries: config::get_keepalive_retries() as u32,
+            missed: 0,
+            seq: 0,
+            interval,
+        }
+    }
+
+    // kept apart from the state so the tick can be awaited while handling PONGs
+    pub fn ticker(&self) -> Interval {
+        time::interval_at(Instant::now() + self.interval, self.interval)
+    }
+
+    // called on every tick, the PING to send if the peer is still alive
+    pub fn ping(&mut self) -> AsyncReturn<Option<Message>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        if self.missed >= self.retries {
+            return Err(format!("Peer not responding after {} pings", self.missed).into());
+        }
+        self.missed += 1;
+        self.seq = self.seq.wrapping_add(1);
+        Ok(Some(Message::Ping(self.seq)))
+    }
+
+    pub fn pong(&mut self, seq: u32) {
+        debug!("Pong {}, {} pings in flight", seq, self.missed);
+        self.missed = 0;
+    }
+}
@@ -0,0 +1,321 @@
+use crate::AsyncReturn;
+use log::*;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs, io,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+struct Lease {
+    name: String,
+    // unix time the client was last seen on the address
+    last_seen: u64,
+    // a session is on the address
+    active: bool,
+}
+
+// where the leases go, written by one snapshot at a time
+struct LeaseFile {
+    path: String,
+    // the version last written
+    written: u64,
+}
+
+// the leases at one version, written out of the lock on the leases
+pub struct Snapshot {
+    file: Arc<Mutex<LeaseFile>>,
+    version: u64,
+    content: String,
+}
+
+impl Snapshot {
+    pub fn write(self) {
+        let mut file = self.file.lock().unwrap();
+        // a newer snapshot got here first
+        if file.written >= self.version {
+            return;
+        }
+        // a crash while writing leaves the old file in place
+        let tmp = format!("{}.tmp", file.path);
+        let res = fs::write(&tmp, self.content).and_then(|_| fs::rename(&tmp, &file.path));
+        match res {
+            Ok(_) => file.written = self.version,
+            Err(e) => error!("Save leases to {} failed: {}", file.path, e),
+        }
+    }
+}
+
+// client addresses kept for their common names across sessions and restarts,
+// stored as a json array of {"name", "ip", "last_seen"}
+pub struct Leases {
+    file: Arc<Mutex<LeaseFile>>,
+    // seconds a lease is kept after the client is gone
+    time: u64,
+    // by address
+    leases: HashMap<String, Lease>,
+    // bumped on every change, `saved` is the last one taken a snapshot of
+    version: u64,
+    saved: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Leases {
+    pub fn load(path: &str, time: u64) -> AsyncReturn<Leases> {
+        let mut leases = HashMap::new();
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let records: Value = serde_json::from_str(&content)?;
+                for record in records.as_array().ok_or("Lease file is not an array")? {
+                    let name = record.get("name").and_then(|name| name.as_str());
+                    let ip = record.get("ip").and_then(|ip| ip.as_str());
+                    let last_seen = record.get("last_seen").and_then(|t| t.as_u64());
+                    match (name, ip, last_seen) {
+                        (Some(name), Some(ip), Some(last_seen)) => {
+                            leases.insert(
+                                ip.to_string(),
+                                Lease {
+                                    name: name.to_string(),
+                                    last_seen,
+                                    active: false,
+                                },
+                            );
+                        }
+                        _ => warn!("Skip invalid lease {}", record),
+                    }
+                }
+                info!("Load {} leases from {}", leases.len(), path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No lease file {}, start without leases", path);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Leases {
+            file: Arc::new(Mutex::new(LeaseFile {
+                path: path.to_string(),
+                written: 0,
+            })),
+            time,
+            leases,
+            version: 0,
+            saved: 0,
+        })
+    }
+
+    fn changed(&mut self) {
+        self.version += 1;
+    }
+
+    // the leases to write if they changed since the last snapshot
+    pub fn snapshot(&mut self) -> Option<Snapshot> {
+        if self.saved == self.version {
+            return None;
+        }
+        self.saved = self.version;
+        let records: Vec<Value> = self
+            .leases
+            .iter()
+            .map(|(ip, lease)| json!({"name": &lease.name, "ip": ip, "last_seen": lease.last_seen}))
+            .collect();
+        Some(Snapshot {
+            file: self.file.clone(),
+            version: self.version,
+            content: Value::from(records).to_string(),
+        })
+    }
+
+    // the leased addresses of one family
+    pub fn ips(&self, v6: bool) -> Vec<String> {
+        self.leases
+            .keys()
+            .filter(|ip| ip.contains(':') == v6)
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove(&mut self, ip: &str) {
+        if self.leases.remove(ip).is_some() {
+            self.changed();
+        }
+    }
+
+    // drops the leases of clients gone for too long,
+    // returns their addresses for the pools to take back
+    pub fn expire(&mut self) -> Vec<String> {
+        let deadline = now().saturating_sub(self.time);
+        let expired: Vec<String> = self
+            .leases
+            .iter()
+            .filter(|(_, lease)| !lease.active && lease.last_seen < deadline)
+            .map(|(ip, _)| ip.clone())
+            .collect();
+        for ip in &expired {
+            let lease = self.leases.remove(ip).unwrap();
+            info!("Lease of {} for {} expires", ip, lease.name);
+        }
+        if !expired.is_empty() {
+            self.changed();
+        }
+        expired
+    }
+
+    // the address `name` had before among those `wanted`, if it is still leased
+    pub fn take(&mut self, name: &str, wanted: impl Fn(&str) -> bool) -> Option<String> {
+        let ip = self
+            .leases
+            .iter()
+            .find(|(ip, lease)| lease.name == name && !lease.active && wanted(ip))
+            .map(|(ip, _)| ip.clone())?;
+        let lease = self.leases.get_mut(&ip).unwrap();
+        lease.active = true;
+        lease.last_seen = now();
+        self.changed();
+        Some(ip)
+    }
+
+    // the address of the client gone the longest among those `wanted`,
+    // taken back from it if it is gone for at least `rest` seconds
+    pub fn evict(&mut self, rest: u64, wanted: impl Fn(&str) -> bool) -> Option<String> {
+        let deadline = now().saturating_sub(rest);
+        let ip = self
+            .leases
+            .iter()
+            .filter(|(ip, lease)| !lease.active && lease.last_seen <= deadline && wanted(ip))
+            .min_by_key(|(_, lease)| lease.last_seen)
+            .map(|(ip, _)| ip.clone())?;
+        let lease = self.leases.remove(&ip).unwrap();
+        info!("Lease of {} for {} is taken back", ip, lease.name);
+        self.changed();
+        Some(ip)
+    }
+
+    // `ip` is newly handed to `name`
+    pub fn grant(&mut self, name: &str, ip: &str) {
+        self.leases.insert(
+            ip.to_string(),
+            Lease {
+                name: name.to_string(),
+                last_seen: now(),
+                active: true,
+            },
+        );
+        self.changed();
+    }
+
+    // the session on `ip` ends, true if the address stays with its client
+    pub fn release(&mut self, ip: &str) -> bool {
+        match self.leases.get_mut(ip) {
+            Some(lease) => {
+                lease.active = false;
+                lease.last_seen = now();
+                self.changed();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // leases without a file yet, in a path of their own
+    fn leases(test: &str, time: u64) -> Leases {
+        let path = std::env::temp_dir().join(format!("vgw-leases-{}-{}", test, std::process::id()));
+        let _ = fs::remove_file(&path);
+        Leases::load(path.to_str().unwrap(), time).unwrap()
+    }
+
+    // `name` left `ip` `ago` seconds ago
+    fn gone(leases: &mut Leases, name: &str, ip: &str, ago: u64) {
+        leases.grant(name, ip);
+        leases.release(ip);
+        leases.leases.get_mut(ip).unwrap().last_seen = now() - ago;
+    }
+
+    #[test]
+    fn grant_release_take() {
+        let mut leases = leases("take", 3600);
+        leases.grant("a", "10.0.0.1");
+        // in use, nobody takes it
+        assert_eq!(leases.take("a", |_| true), None);
+        assert!(leases.release("10.0.0.1"));
+        assert!(!leases.release("10.0.0.2"));
+        assert_eq!(leases.take("b", |_| true), None);
+        assert_eq!(leases.take("a", |ip| ip.starts_with("fd00:")), None);
+        assert_eq!(leases.take("a", |_| true), Some("10.0.0.1".to_string()));
+    }
+
+    #[test]
+    fn expire() {
+        let mut leases = leases("expire", 3600);
+        gone(&mut leases, "a", "10.0.0.1", 7200);
+        gone(&mut leases, "b", "10.0.0.2", 60);
+        // in use for long, it stays
+        leases.grant("c", "10.0.0.3");
+        leases.leases.get_mut("10.0.0.3").unwrap().last_seen = now() - 7200;
+        assert_eq!(leases.expire(), vec!["10.0.0.1".to_string()]);
+        assert!(leases.expire().is_empty());
+        let mut ips = leases.ips(false);
+        ips.sort();
+        assert_eq!(ips, vec!["10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn evict_the_oldest() {
+        let mut leases = leases("evict", 3600);
+        gone(&mut leases, "a", "10.0.0.1", 60);
+        gone(&mut leases, "b", "10.0.0.2", 600);
+        gone(&mut leases, "c", "fd00::2", 900);
+        leases.grant("d", "10.0.0.3");
+        let v4 = |ip: &str| !ip.contains(':');
+        assert_eq!(leases.evict(0, v4), Some("10.0.0.2".to_string()));
+        // not rested long enough
+        assert_eq!(leases.evict(120, v4), None);
+        assert_eq!(leases.evict(0, v4), Some("10.0.0.1".to_string()));
+        assert_eq!(leases.evict(0, v4), None);
+        assert_eq!(leases.take("b", |_| true), None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut saved = leases("save", 3600);
+        saved.grant("a", "10.0.0.1");
+        saved.grant("b", "fd00::2");
+        let snapshot = saved.snapshot().unwrap();
+        // nothing changed since
+        assert!(saved.snapshot().is_none());
+        snapshot.write();
+
+        let path = saved.file.lock().unwrap().path.clone();
+        let mut loaded = Leases::load(&path, 3600).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded.ips(true), vec!["fd00::2"]);
+        // a session is on none of them after a restart
+        assert_eq!(loaded.take("a", |_| true), Some("10.0.0.1".to_string()));
+    }
+
+    #[test]
+    fn older_snapshot_is_skipped() {
+        let mut leases = leases("order", 3600);
+        leases.grant("a", "10.0.0.1");
+        let older = leases.snapshot().unwrap();
+        leases.grant("b", "10.0.0.2");
+        leases.snapshot().unwrap().write();
+        older.write();
+
+        let path = leases.file.lock().unwrap().path.clone();
+        let loaded = Leases::load(&path, 3600).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded.ips(false).len(), 2);
+    }
+}
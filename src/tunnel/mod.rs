@@ -1,5 +1,13 @@
 pub mod action;
+pub mod codec;
 pub mod ippool;
+pub mod keepalive;
+mod lease;
+pub mod packet;
+pub mod prefix;
 mod tun;
 
-pub use self::tun::create_tun;
+pub use self::tun::{
+    add_route6, create_tun, create_tun_queues, del_route6, del_route6_blocking, tun_name,
+    tun_stream, TunQueue, TunStream,
+};
@@ -0,0 +1,229 @@
+use etherparse::{Ipv4HeaderSlice, Ipv6HeaderSlice};
+use std::net::IpAddr;
+
+// the source and destination of an ip packet, None if it's not one
+pub fn addresses(pkt: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match pkt.first()? >> 4 {
+        4 => Ipv4HeaderSlice::from_slice(pkt)
+            .ok()
+            .map(|ip4h| (ip4h.source_addr().into(), ip4h.destination_addr().into())),
+        6 => Ipv6HeaderSlice::from_slice(pkt)
+            .ok()
+            .map(|ip6h| (ip6h.source_addr().into(), ip6h.destination_addr().into())),
+        _ => None,
+    }
+}
+
+pub fn source(pkt: &[u8]) -> Option<IpAddr> {
+    addresses(pkt).map(|(src, _)| src)
+}
+
+pub fn destination(pkt: &[u8]) -> Option<IpAddr> {
+    addresses(pkt).map(|(_, dst)| dst)
+}
+
+// what of the offending packet an ICMP error carries,
+// the reply stays within the minimum MTU of its family
+const QUOTE4: usize = 576 - 28;
+const QUOTE6: usize = 1280 - 48;
+
+// the ones' complement sum of rfc 1071
+fn checksum(sum: u32, data: &[u8]) -> u32 {
+    data.chunks(2).fold(sum, |sum, word| {
+        let hi = (word[0] as u32) << 8;
+        sum + hi + word.get(1).map(|lo| *lo as u32).unwrap_or(0)
+    })
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// true if `pkt` must not be answered with an ICMP error,
+// itself an error, a trailing fragment or from nobody in particular
+fn is_unanswerable(pkt: &[u8], src: &IpAddr) -> bool {
+    if src.is_unspecified() || src.is_multicast() {
+        return true;
+    }
+    match src {
+        IpAddr::V4(_) => {
+            let ihl = ((pkt[0] & 0x0f) as usize) * 4;
+            let fragment = u16::from_be_bytes([pkt[6], pkt[7]]) & 0x1fff;
+            // ICMP, echo and friends are fine but destination unreachable,
+            // source quench, redirect, time exceeded and parameter problem are errors
+            fragment != 0 || (pkt[9] == 1 && matches!(pkt.get(ihl), Some(3..=5) | Some(11..=12)))
+        }
+        // ICMPv6 errors are the types below 128
+        IpAddr::V6(_) => pkt[6] == 58 && matches!(pkt.get(40), Some(0..=127)),
+    }
+}
+
+// an ICMP host unreachable or ICMPv6 address unreachable from `from`
+// to the source of `pkt`, None if `pkt` is not to be answered
+pub fn unreachable(pkt: &[u8], from: IpAddr) -> Option<Vec<u8>> {
+    let (src, _) = addresses(pkt)?;
+    if is_unanswerable(pkt, &src) {
+        return None;
+    }
+    match (from, src) {
+        (IpAddr::V4(from), IpAddr::V4(src)) => {
+            let quote = &pkt[..pkt.len().min(QUOTE4)];
+            let len = 28 + quote.len();
+            let mut reply = Vec::with_capacity(len);
+            reply.extend_from_slice(&[0x45, 0]);
+            reply.extend_from_slice(&(len as u16).to_be_bytes());
+            // id, flags, ttl, ICMP, checksum
+            reply.extend_from_slice(&[0, 0, 0, 0, 64, 1, 0, 0]);
+            reply.extend_from_slice(&from.octets());
+            reply.extend_from_slice(&src.octets());
+            let sum = fold(checksum(0, &reply));
+            reply[10..12].copy_from_slice(&sum.to_be_bytes());
+
+            // destination unreachable, host unreachable
+            reply.extend_from_slice(&[3, 1, 0, 0, 0, 0, 0, 0]);
+            reply.extend_from_slice(quote);
+            let sum = fold(checksum(0, &reply[20..]));
+            reply[22..24].copy_from_slice(&sum.to_be_bytes());
+            Some(reply)
+        }
+        (IpAddr::V6(from), IpAddr::V6(src)) => {
+            let quote = &pkt[..pkt.len().min(QUOTE6)];
+            let payload_len = 8 + quote.len();
+            let mut reply = Vec::with_capacity(40 + payload_len);
+            reply.extend_from_slice(&[0x60, 0, 0, 0]);
+            reply.extend_from_slice(&(payload_len as u16).to_be_bytes());
+            // ICMPv6, hop limit
+            reply.extend_from_slice(&[58, 64]);
+            reply.extend_from_slice(&from.octets());
+            reply.extend_from_slice(&src.octets());
+
+            // destination unreachable, address unreachable
+            reply.extend_from_slice(&[1, 3, 0, 0, 0, 0, 0, 0]);
+            reply.extend_from_slice(quote);
+            // over the pseudo header of rfc 8200 too
+            let sum = checksum(0, &reply[8..40]);
+            let sum = checksum(sum, &(payload_len as u32).to_be_bytes());
+            let sum = checksum(sum, &[0, 0, 0, 58]);
+            let sum = fold(checksum(sum, &reply[40..]));
+            reply[42..44].copy_from_slice(&sum.to_be_bytes());
+            Some(reply)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // an ipv4 packet of `len` bytes with protocol `proto` and `payload` up front
+    fn ipv4(src: [u8; 4], dst: [u8; 4], proto: u8, payload: &[u8], len: usize) -> Vec<u8> {
+        let mut pkt = vec![0x45, 0];
+        pkt.extend_from_slice(&(len as u16).to_be_bytes());
+        pkt.extend_from_slice(&[0, 1, 0, 0, 64, proto, 0, 0]);
+        pkt.extend_from_slice(&src);
+        pkt.extend_from_slice(&dst);
+        let sum = fold(checksum(0, &pkt));
+        pkt[10..12].copy_from_slice(&sum.to_be_bytes());
+        pkt.extend_from_slice(payload);
+        pkt.resize(len, 0xab);
+        pkt
+    }
+
+    fn ipv6(src: &str, dst: &str, next: u8, payload: &[u8], len: usize) -> Vec<u8> {
+        let (src, dst): (std::net::Ipv6Addr, std::net::Ipv6Addr) =
+            (src.parse().unwrap(), dst.parse().unwrap());
+        let mut pkt = vec![0x60, 0, 0, 0];
+        pkt.extend_from_slice(&((len - 40) as u16).to_be_bytes());
+        pkt.extend_from_slice(&[next, 64]);
+        pkt.extend_from_slice(&src.octets());
+        pkt.extend_from_slice(&dst.octets());
+        pkt.extend_from_slice(payload);
+        pkt.resize(len, 0xab);
+        pkt
+    }
+
+    #[test]
+    fn checksum_of_rfc1071() {
+        // the example of rfc 1071 section 3
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(fold(checksum(0, &data)), !0xddf2);
+        // an odd byte is padded with zero
+        assert_eq!(checksum(0, &[0x12]), 0x1200);
+    }
+
+    #[test]
+    fn unreachable4() {
+        let pkt = ipv4([10, 0, 0, 2], [10, 0, 0, 9], 17, &[], 61);
+        let from: IpAddr = "10.0.0.1".parse().unwrap();
+        let reply = unreachable(&pkt, from).unwrap();
+        assert_eq!(reply.len(), 28 + pkt.len());
+        assert_eq!(addresses(&reply), Some((from, "10.0.0.2".parse().unwrap())));
+        // both sums verify to zero
+        assert_eq!(fold(checksum(0, &reply[..20])), 0);
+        assert_eq!(fold(checksum(0, &reply[20..])), 0);
+        assert_eq!(&reply[20..22], &[3, 1]);
+        assert_eq!(&reply[28..], &pkt[..]);
+    }
+
+    #[test]
+    fn unreachable4_quotes_at_most_576() {
+        let pkt = ipv4([10, 0, 0, 2], [10, 0, 0, 9], 6, &[], 1400);
+        let reply = unreachable(&pkt, "10.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(reply.len(), 576);
+        assert_eq!(u16::from_be_bytes([reply[2], reply[3]]), 576);
+        assert_eq!(fold(checksum(0, &reply[20..])), 0);
+    }
+
+    #[test]
+    fn unreachable6() {
+        let pkt = ipv6("fd00::2", "fd00::9", 17, &[], 77);
+        let from: IpAddr = "fd00::1".parse().unwrap();
+        let reply = unreachable(&pkt, from).unwrap();
+        assert_eq!(reply.len(), 48 + pkt.len());
+        assert_eq!(addresses(&reply), Some((from, "fd00::2".parse().unwrap())));
+        assert_eq!(&reply[40..42], &[1, 3]);
+        let payload_len = (reply.len() - 40) as u32;
+        let sum = checksum(0, &reply[8..40]);
+        let sum = checksum(sum, &payload_len.to_be_bytes());
+        let sum = checksum(sum, &[0, 0, 0, 58]);
+        assert_eq!(fold(checksum(sum, &reply[40..])), 0);
+    }
+
+    #[test]
+    fn unreachable6_quotes_at_most_1280() {
+        let pkt = ipv6("fd00::2", "fd00::9", 6, &[], 1400);
+        let reply = unreachable(&pkt, "fd00::1".parse().unwrap()).unwrap();
+        assert_eq!(reply.len(), 1280);
+    }
+
+    #[test]
+    fn errors_are_not_answered() {
+        let from: IpAddr = "10.0.0.1".parse().unwrap();
+        // host unreachable itself
+        let pkt = ipv4([10, 0, 0, 2], [10, 0, 0, 9], 1, &[3, 1], 36);
+        assert_eq!(unreachable(&pkt, from), None);
+        // but an echo request is
+        let pkt = ipv4([10, 0, 0, 2], [10, 0, 0, 9], 1, &[8, 0], 36);
+        assert!(unreachable(&pkt, from).is_some());
+        // a trailing fragment
+        let mut pkt = ipv4([10, 0, 0, 2], [10, 0, 0, 9], 17, &[], 36);
+        pkt[7] = 1;
+        assert_eq!(unreachable(&pkt, from), None);
+        // from nobody
+        let pkt = ipv4([0, 0, 0, 0], [10, 0, 0, 9], 17, &[], 36);
+        assert_eq!(unreachable(&pkt, from), None);
+
+        let from: IpAddr = "fd00::1".parse().unwrap();
+        let pkt = ipv6("fd00::2", "fd00::9", 58, &[1, 3], 56);
+        assert_eq!(unreachable(&pkt, from), None);
+        let pkt = ipv6("fd00::2", "fd00::9", 58, &[128, 0], 56);
+        assert!(unreachable(&pkt, from).is_some());
+        // across families
+        let pkt = ipv4([10, 0, 0, 2], [10, 0, 0, 9], 17, &[], 36);
+        assert_eq!(unreachable(&pkt, from), None);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::AsyncReturn;
+use std::fmt;
+use std::net::IpAddr;
+
+// an ipv4 or ipv6 network, `net/len` or a single address
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Prefix {
+    net: IpAddr,
+    len: u8,
+}
+
+fn bits(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn to_num(ip: &IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(*ip) as u128,
+        IpAddr::V6(ip) => u128::from(*ip),
+    }
+}
+
+// the network part of a `bits` wide address, `len` bits long
+fn net_mask(bits: u8, len: u8) -> u128 {
+    let host = 1u128
+        .checked_shl((bits - len) as u32)
+        .map_or(u128::MAX, |n| n - 1);
+    !host
+}
+
+impl Prefix {
+    pub fn parse(s: &str) -> AsyncReturn<Prefix> {
+        let (net, len) = match s.split_once('/') {
+            Some((net, len)) => {
+                let net = net.parse::<IpAddr>()?;
+                (net, len.parse::<u8>()?)
+            }
+            None => {
+                let net = s.parse::<IpAddr>()?;
+                (net, bits(&net))
+            }
+        };
+        if len > bits(&net) {
+            return Err(format!("Invalid prefix length in {}", s).into());
+        }
+        if to_num(&net) & !net_mask(bits(&net), len) != 0 {
+            return Err(format!("{} is not a network address", s).into());
+        }
+        Ok(Prefix { net, len })
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.net.is_ipv6()
+    }
+
+    // `other` lies within this prefix
+    pub fn covers(&self, other: &Prefix) -> bool {
+        self.len <= other.len && self.contains(&other.net)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.net.is_ipv4() == ip.is_ipv4()
+            && to_num(ip) & net_mask(bits(ip), self.len) == to_num(&self.net)
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.net, self.len)
+    }
+}
+
+impl fmt::Debug for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+struct Node<T> {
+    value: Option<T>,
+    children: [Option<Box<Node<T>>>; 2],
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            value: None,
+            children: [None, None],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.iter().all(Option::is_none)
+    }
+}
+
+// bit `depth` of a `bits` wide address, from the most significant one
+fn bit(num: u128, bits: u8, depth: u8) -> usize {
+    ((num >> (bits - 1 - depth)) & 1) as usize
+}
+
+// a binary trie from prefixes to values, looked up by longest match
+pub struct PrefixMap<T> {
+    v4: Node<T>,
+    v6: Node<T>,
+}
+
+impl<T> Default for PrefixMap<T> {
+    fn default() -> Self {
+        PrefixMap::new()
+    }
+}
+
+impl<T> PrefixMap<T> {
+    pub fn new() -> PrefixMap<T> {
+        PrefixMap {
+            v4: Node::new(),
+            v6: Node::new(),
+        }
+    }
+
+    fn root(&mut self, ip: &IpAddr) -> &mut Node<T> {
+        match ip {
+            IpAddr::V4(_) => &mut self.v4,
+            IpAddr::V6(_) => &mut self.v6,
+        }
+    }
+
+    // returns the value `prefix` had
+    pub fn insert(&mut self, prefix: Prefix, value: T) -> Option<T> {
+        let (num, bits) = (to_num(&prefix.net), bits(&prefix.net));
+        let mut node = self.root(&prefix.net);
+        for depth in 0..prefix.len {
+            node =
+                node.children[bit(num, bits, depth)].get_or_insert_with(|| Box::new(Node::new()));
+        }
+        node.value.replace(value)
+    }
+
+    pub fn remove(&mut self, prefix: &Prefix) -> Option<T> {
+        fn remove<T>(node: &mut Node<T>, num: u128, bits: u8, depth: u8, len: u8) -> Option<T> {
+            if depth == len {
+                return node.value.take();
+            }
+            let b = bit(num, bits, depth);
+            let child = node.children[b].as_mut()?;
+            let value = remove(child, num, bits, depth + 1, len);
+            if child.is_empty() {
+                node.children[b] = None;
+            }
+            value
+        }
+        let (num, bits) = (to_num(&prefix.net), bits(&prefix.net));
+        remove(self.root(&prefix.net), num, bits, 0, prefix.len)
+    }
+
+    // the value of the most specific prefix containing `ip`
+    pub fn lookup(&self, ip: &IpAddr) -> Option<&T> {
+        let (num, bits) = (to_num(ip), bits(ip));
+        let mut node = match ip {
+            IpAddr::V4(_) => &self.v4,
+            IpAddr::V6(_) => &self.v6,
+        };
+        let mut found = node.value.as_ref();
+        for depth in 0..bits {
+            node = match &node.children[bit(num, bits, depth)] {
+                Some(child) => child,
+                None => break,
+            };
+            if node.value.is_some() {
+                found = node.value.as_ref();
+            }
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> Prefix {
+        Prefix::parse(s).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(prefix("10.0.0.1").to_string(), "10.0.0.1/32");
+        assert_eq!(prefix("fd00::/8").to_string(), "fd00::/8");
+        assert_eq!(prefix("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert!(Prefix::parse("10.0.0.1/24").is_err());
+        assert!(Prefix::parse("10.0.0.0/33").is_err());
+        assert!(Prefix::parse("fd00::/129").is_err());
+        assert!(Prefix::parse("10.0.0/24").is_err());
+    }
+
+    #[test]
+    fn covers() {
+        assert!(prefix("10.0.0.0/8").covers(&prefix("10.1.0.0/16")));
+        assert!(prefix("10.0.0.0/8").covers(&prefix("10.0.0.0/8")));
+        assert!(!prefix("10.1.0.0/16").covers(&prefix("10.0.0.0/8")));
+        assert!(!prefix("10.0.0.0/8").covers(&prefix("11.0.0.0/16")));
+        assert!(!prefix("0.0.0.0/0").covers(&prefix("::/0")));
+    }
+
+    #[test]
+    fn longest_match() {
+        let mut map = PrefixMap::new();
+        map.insert(prefix("0.0.0.0/0"), "default");
+        map.insert(prefix("10.0.0.0/8"), "8");
+        map.insert(prefix("10.1.0.0/16"), "16");
+        map.insert(prefix("10.1.2.3"), "32");
+        assert_eq!(map.lookup(&ip("10.1.2.3")), Some(&"32"));
+        assert_eq!(map.lookup(&ip("10.1.2.4")), Some(&"16"));
+        assert_eq!(map.lookup(&ip("10.2.0.1")), Some(&"8"));
+        assert_eq!(map.lookup(&ip("192.168.0.1")), Some(&"default"));
+        // the families do not mix
+        assert_eq!(map.lookup(&ip("::1")), None);
+    }
+
+    #[test]
+    fn ipv6_match() {
+        let mut map = PrefixMap::new();
+        map.insert(prefix("fd00::/64"), "pool");
+        map.insert(prefix("fd00::2"), "host");
+        assert_eq!(map.lookup(&ip("fd00::2")), Some(&"host"));
+        assert_eq!(map.lookup(&ip("fd00::3")), Some(&"pool"));
+        assert_eq!(map.lookup(&ip("fd00:0:0:1::2")), None);
+        assert_eq!(map.lookup(&ip("10.0.0.2")), None);
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut map = PrefixMap::new();
+        assert_eq!(map.insert(prefix("10.0.0.0/8"), 1), None);
+        assert_eq!(map.insert(prefix("10.1.0.0/16"), 2), None);
+        assert_eq!(map.insert(prefix("10.1.0.0/16"), 3), Some(2));
+        assert_eq!(map.remove(&prefix("10.2.0.0/16")), None);
+        assert_eq!(map.remove(&prefix("10.1.0.0/16")), Some(3));
+        assert_eq!(map.lookup(&ip("10.1.0.1")), Some(&1));
+        assert_eq!(map.remove(&prefix("10.0.0.0/8")), Some(1));
+        assert_eq!(map.lookup(&ip("10.1.0.1")), None);
+        // nothing is left behind
+        assert!(map.v4.is_empty());
+    }
+}
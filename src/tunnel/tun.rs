@@ -1,14 +1,122 @@
 use crate::AsyncReturn;
+use bytes::Bytes;
+use futures::{ready, Stream};
 use log::*;
-use tun::AsyncDevice;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::pin::Pin;
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tun::{AsyncDevice, Device};
 
-fn create_tun_with_ip(ip: &str) -> AsyncReturn<AsyncDevice> {
+const MTU: i32 = 1350;
+
+// every packet is read into a buffer of this size, taken from the pool
+// of its tun and given back once the packet is dropped, wherever it waits
+const BUF: usize = 2048;
+// the idle buffers a tun keeps, the rest are freed
+const POOL: usize = 1024;
+
+// the idle read buffers of a tun
+struct BufPool(Mutex<Vec<Box<[u8]>>>);
+
+impl BufPool {
+    fn take(&self) -> Box<[u8]> {
+        let buf = self.0.lock().unwrap().pop();
+        // zeroed once, when it is made
+        buf.unwrap_or_else(|| vec![0; BUF].into_boxed_slice())
+    }
+}
+
+// a packet read into a buffer of the pool
+struct Pooled {
+    buf: Option<Box<[u8]>>,
+    len: usize,
+    pool: Arc<BufPool>,
+}
+
+impl AsRef<[u8]> for Pooled {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf.as_ref().unwrap()[..self.len]
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        let mut idle = self.pool.0.lock().unwrap();
+        if idle.len() < POOL {
+            idle.push(self.buf.take().unwrap());
+        }
+    }
+}
+
+// a tun read as packets in pooled buffers, and written straight from the packets
+pub struct TunStream<T = AsyncDevice> {
+    io: T,
+    pool: Arc<BufPool>,
+    // the buffer of the read in progress
+    buf: Option<Box<[u8]>>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> TunStream<T> {
+    pub fn new(io: T) -> TunStream<T> {
+        TunStream {
+            io,
+            pool: Arc::new(BufPool(Mutex::new(vec![]))),
+            buf: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    // a write is one packet
+    pub async fn send(&mut self, pkt: Bytes) -> io::Result<()> {
+        self.io.write(&pkt).await.map(|_| ())
+    }
+}
+
+// the next packet, None once the tun is closed
+impl<T: AsyncRead + Unpin> Stream for TunStream<T> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let pool = &this.pool;
+        let buf = this.buf.get_or_insert_with(|| pool.take());
+        // a read returns one whole packet
+        let mut read = ReadBuf::new(buf);
+        ready!(Pin::new(&mut this.io).poll_read(cx, &mut read))?;
+        let len = read.filled().len();
+        if len == 0 {
+            return Poll::Ready(None);
+        }
+        let pkt = Pooled {
+            buf: this.buf.take(),
+            len,
+            pool: this.pool.clone(),
+        };
+        Poll::Ready(Some(Ok(Bytes::from_owner(pkt))))
+    }
+}
+
+pub fn tun_stream(dev: AsyncDevice) -> TunStream {
+    TunStream::new(dev)
+}
+
+fn create_tun_with_ip(ip: &str, queues: usize) -> AsyncReturn<AsyncDevice> {
     let mut config = tun::Configuration::default();
 
     config
         .address(ip)
         .netmask((255, 255, 255, 255))
-        .mtu(1350)
+        .mtu(MTU)
+        .queues(queues)
         .up();
 
     #[cfg(target_os = "linux")]
@@ -19,8 +127,216 @@ fn create_tun_with_ip(ip: &str) -> AsyncReturn<AsyncDevice> {
     Ok(tun::create_as_async(&config).unwrap())
 }
 
-pub fn create_tun(addr: &str) -> AsyncReturn<AsyncDevice> {
-    let dev = create_tun_with_ip(addr)?;
-    info!("Crate tun : {}", addr);
+pub fn tun_name(dev: &AsyncDevice) -> String {
+    dev.get_ref().name().to_string()
+}
+
+// `ip -6 <args> dev <tun>`, the tun crate only configures ipv4
+fn ip6_command(name: &str, args: &[&str]) -> Command {
+    info!("ip -6 {} dev {}", args.join(" "), name);
+    let mut command = Command::new("ip");
+    command.arg("-6").args(args).arg("dev").arg(name);
+    command
+}
+
+fn ip6_status(args: &[&str], output: Output) -> AsyncReturn<()> {
+    if !output.status.success() {
+        return Err(format!(
+            "ip -6 {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// blocks the thread, for setting up the device
+fn ip6(name: &str, args: &[&str]) -> AsyncReturn<()> {
+    let output = ip6_command(name, args).output()?;
+    ip6_status(args, output)
+}
+
+async fn ip6_async(name: &str, args: &[&str]) -> AsyncReturn<()> {
+    let output = tokio::process::Command::from(ip6_command(name, args))
+        .output()
+        .await?;
+    ip6_status(args, output)
+}
+
+pub fn create_tun(addr: &str, addr6: Option<&str>) -> AsyncReturn<AsyncDevice> {
+    let (dev, _) = create_tun_queues(addr, addr6, 1)?;
     Ok(dev)
 }
+
+// a tun with `queues` queues, the device itself reads and writes the first one
+pub fn create_tun_queues(
+    addr: &str,
+    addr6: Option<&str>,
+    queues: usize,
+) -> AsyncReturn<(AsyncDevice, Vec<TunQueue>)> {
+    let mut dev = create_tun_with_ip(addr, queues)?;
+    info!("Crate tun : {} with {} queues", addr, queues);
+    if let Some(addr6) = addr6 {
+        ip6(&tun_name(&dev), &["addr", "add", &format!("{}/128", addr6)])?;
+    }
+    let mut rest = vec![];
+    for index in 1..queues {
+        let queue = dev
+            .get_mut()
+            .queue(index)
+            .ok_or_else(|| format!("No tun queue {}", index))?;
+        queue.set_nonblock()?;
+        rest.push(TunQueue::new(queue.as_raw_fd())?);
+    }
+    Ok((dev, rest))
+}
+
+// one more queue of a multi-queue tun, for another reader and writer
+pub struct TunQueue(AsyncFd<File>);
+
+impl TunQueue {
+    fn new(fd: i32) -> io::Result<TunQueue> {
+        // the device keeps its fd, the queue gets its own on the same file
+        let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) }).try_clone()?;
+        Ok(TunQueue(AsyncFd::new(file)?))
+    }
+
+    pub fn into_stream(self) -> TunStream<TunQueue> {
+        TunStream::new(self)
+    }
+}
+
+impl AsyncRead for TunQueue {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            // SAFETY: read only writes into the unfilled part, what it wrote is initialized
+            let unfilled =
+                unsafe { &mut *(buf.unfilled_mut() as *mut [MaybeUninit<u8>] as *mut [u8]) };
+            match guard.try_io(|file| file.get_ref().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    unsafe { buf.assume_init(n) };
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TunQueue {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|file| file.get_ref().write(buf)) {
+                Ok(res) => return Poll::Ready(res),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+// sends the ipv6 `route` into the tun `name`
+pub async fn add_route6(name: &str, route: &str) -> AsyncReturn<()> {
+    ip6_async(name, &["route", "add", route]).await
+}
+
+pub async fn del_route6(name: &str, route: &str) -> AsyncReturn<()> {
+    ip6_async(name, &["route", "del", route]).await
+}
+
+// for where nothing can be awaited, blocks the thread
+pub fn del_route6_blocking(name: &str, route: &str) -> AsyncReturn<()> {
+    ip6(name, &["route", "del", route])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::collections::VecDeque;
+
+    // a tun giving one packet per read
+    struct Packets(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for Packets {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if let Some(pkt) = self.0.pop_front() {
+                buf.put_slice(&pkt);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Packets {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn idle<T>(tun: &TunStream<T>) -> usize {
+        tun.pool.0.lock().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn packets_give_their_buffers_back() {
+        let packets = (0..4u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let mut tun = TunStream::new(Packets(packets));
+        let first = tun.next().await.unwrap().unwrap();
+        let second = tun.next().await.unwrap().unwrap();
+        assert_eq!(&first[..], &[0; 100][..]);
+        assert_eq!(&second[..], &[1; 101][..]);
+        assert_eq!(idle(&tun), 0);
+
+        // a packet held elsewhere keeps its buffer, nothing else
+        let held = second.clone();
+        drop(first);
+        drop(second);
+        assert_eq!(idle(&tun), 1);
+        let third = tun.next().await.unwrap().unwrap();
+        assert_eq!(&third[..], &[2; 102][..]);
+        assert_eq!(idle(&tun), 0);
+        drop(held);
+        drop(third);
+        assert_eq!(idle(&tun), 2);
+
+        tun.next().await.unwrap().unwrap();
+        // the tun is closed once a read returns nothing
+        assert!(tun.next().await.is_none());
+    }
+}